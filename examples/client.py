@@ -7,20 +7,64 @@ import numpy as np
 import websockets
 from shortuuid import uuid
 import argparse
+import struct
 
 
-async def hello(host: str, port: int, hash: str = "dev"):
+def frame(hash: str, payload: bytes, kind: int = 0, flags: int = 0) -> bytes:
+    """Prefix a payload with the binary frame header expected by the server."""
+    hash_bytes = hash.encode("utf-8")
+    header = struct.pack("<4sBBHHQ", b"TVIS", 1, kind, flags, len(hash_bytes), len(payload))
+    return header + hash_bytes + payload
+
+
+def chunk(hash: str, upload_id: str, offset: int, data: bytes) -> bytes:
+    """Frame part of an array sent as a chunked upload."""
+    id_bytes = upload_id.encode("utf-8")
+    payload = struct.pack("<H", len(id_bytes)) + id_bytes + struct.pack("<Q", offset) + data
+    return frame(hash, payload, kind=1)
+
+
+async def send_chunked(websocket, hash: str, arr: np.ndarray, chunk_size: int):
+    """Send an array as a chunked upload, resuming where the server left off."""
+    data = arr.tobytes()
+    upload_id = uuid()
+    msg = {"type": "upload_start", "upload_id": upload_id, "size": len(data), "hash": hash}
+    await websocket.send(json.dumps({**msg, "shape": arr.shape, "dtype": arr.dtype.name}))
+
+    while True:
+        status = json.loads(await websocket.recv())
+        if status.get("type") == "upload_status":
+            break
+
+    for offset in range(status["next_offset"], len(data), chunk_size):
+        await websocket.send(chunk(hash, upload_id, offset, data[offset : offset + chunk_size]))
+
+
+async def hello(host: str, port: int, hash: str = "dev", chunk_size: int = 0):
     uri = f"ws://{host}:{port}/ws"
     async with websockets.connect(uri) as websocket:
         # Define the array we want to send
         arr = np.random.randint(0, 2048, (25, 1, 512, 512, 1), dtype=np.uint16)
 
-        # Send the header first
-        msg = json.dumps({"shape": arr.shape, "dtype": arr.dtype.name, "hash": hash})
-        await websocket.send(msg)
+        if chunk_size > 0:
+            await send_chunked(websocket, hash, arr, chunk_size)
+        else:
+            # Send the header first
+            msg = json.dumps({"type": "header", "shape": arr.shape, "dtype": arr.dtype.name, "hash": hash})
+            await websocket.send(msg)
+
+            # Send the array
+            await websocket.send(frame(hash, arr.tobytes()))
 
-        # Send the array
-        await websocket.send(hash.encode("utf-8") + arr.tobytes())
+        # Wait for the server to report whether the array reached a viewer
+        while True:
+            reply = json.loads(await websocket.recv())
+            if reply.get("type") == "delivery":
+                if reply["delivered"]:
+                    print(f"Delivered to {reply['viewers']} viewer(s)")
+                else:
+                    print(f"Not delivered ({reply['reason']}): {reply['message']}")
+                break
 
         # for chunk in np.array_split(arr, arr.shape[0], axis=0):
         #     print("Sending chunk...")
@@ -38,6 +82,7 @@ if __name__ == "__main__":
     parser.add_argument("--host", type=str, default="localhost")
     parser.add_argument("--port", type=int, default=8765)
     parser.add_argument("--hash", type=str, default="dev")
+    parser.add_argument("--chunk-size", type=int, default=0, help="send the array in chunks of this many bytes")
     args = parser.parse_args()
 
-    asyncio.run(asyncio.wait_for(hello(args.host, args.port, args.hash), timeout=5))
+    asyncio.run(asyncio.wait_for(hello(args.host, args.port, args.hash, args.chunk_size), timeout=5))
@@ -0,0 +1,351 @@
+//! HTTP endpoints for arrays
+//!
+//! Arrays can be sent to viewers without holding a websocket open:
+//!
+//! ```not_rust
+//! curl -X POST http://localhost:8765/api/arrays/dev \
+//!     -H "X-Tunnelvision-Shape: 25,1,512,512,1" \
+//!     -H "X-Tunnelvision-Dtype: uint16" \
+//!     --data-binary @array.bin
+//! ```
+//!
+//! or, for arrays saved with `np.save` or `np.savez`, without any headers:
+//!
+//! ```not_rust
+//! curl -X POST http://localhost:8765/api/arrays/dev --data-binary @array.npy
+//! ```
+//!
+//! NIfTI volumes (`.nii` or `.nii.gz`) and multi-page TIFF files are recognized the same way. Files on the disk of the
+//! server can be opened without uploading them:
+//!
+//! ```not_rust
+//! curl -X POST http://localhost:8765/api/open/dev \
+//!     -H "Content-Type: application/json" \
+//!     -d '{"path": "/data/brain.nii.gz"}'
+//! ```
+//!
+//! The same goes for a directory of DICOM files, of which the series with the most slices is
+//! opened, unless another is selected by its series instance UID:
+//!
+//! ```not_rust
+//! curl -X POST http://localhost:8765/api/open/dev \
+//!     -H "Content-Type: application/json" \
+//!     -d '{"path": "/data/ct", "series": "1.2.840.113619.2.55.3"}'
+//! ```
+//!
+//! Zarr stores opened this way are served key by key, see [`crate::zarr`].
+//!
+//! The array a viewer is showing can be fetched again, in whole or in part:
+//!
+//! ```not_rust
+//! curl http://localhost:8765/api/arrays/dev -H "Range: bytes=0-1023"
+//! ```
+//!
+//! When the server builds pyramids (`--pyramid`), viewers are sent the coarsest level of a large
+//! array, and fetch the finer ones by their level, where level `0` is full resolution:
+//!
+//! ```not_rust
+//! curl http://localhost:8765/api/arrays/dev?level=1
+//! ```
+
+use std::ops::Bound;
+use std::sync::Arc;
+
+use axum::body::{boxed, Body, BoxBody, Bytes};
+use axum::extract::{Path, Query, State, TypedHeader};
+use axum::http::{header, HeaderMap, Response, StatusCode};
+use axum::response::IntoResponse;
+use axum::Json;
+use serde::Deserialize;
+
+use crate::frame::{Frame, FrameKind};
+use crate::message::{ArrayHeader, DeliveryFailure, Envelope};
+use crate::volume::Volume;
+use crate::{images, nifti, npy};
+use crate::{open_requested, publish_header, publish_volume, read_blocking, relay_frame, AppState};
+
+/// Header with the comma-separated shape of an array
+pub const SHAPE_HEADER: &str = "x-tunnelvision-shape";
+
+/// Header with the NumPy name of the data type of an array
+pub const DTYPE_HEADER: &str = "x-tunnelvision-dtype";
+
+/// Read the shape and dtype of an array from the request headers
+fn array_header(hash: String, headers: &HeaderMap) -> Result<ArrayHeader, String> {
+    let get = |name: &str| {
+        headers
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .ok_or_else(|| format!("missing `{name}` header"))
+    };
+
+    let shape = get(SHAPE_HEADER)?
+        .split(',')
+        .map(|dim| dim.trim().parse::<usize>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| format!("invalid shape: {err}"))?;
+    let dtype = get(DTYPE_HEADER)?.trim().to_owned();
+
+    Ok(ArrayHeader {
+        hash,
+        shape,
+        dtype,
+        ..Default::default()
+    })
+}
+
+/// Read the body of a request as a NumPy, NIfTI or TIFF file, telling them apart by the content
+/// type or, if no shape is given, the magic bytes. Returns `None` for raw array data.
+async fn read_file(headers: &HeaderMap, body: Bytes, max_len: u64) -> Option<Result<Volume, String>> {
+    let content_type = headers.get(header::CONTENT_TYPE).and_then(|v| v.to_str().ok());
+    let format = match content_type {
+        Some("application/x-npy" | "application/x-npz") => FrameKind::Npy,
+        Some("application/x-nifti" | "application/gzip") => FrameKind::Nifti,
+        Some("image/tiff") => FrameKind::Tiff,
+        _ if headers.contains_key(SHAPE_HEADER) => return None,
+        _ if body.starts_with(npy::NPY_MAGIC) || body.starts_with(npy::NPZ_MAGIC) => FrameKind::Npy,
+        _ if images::TIFF_MAGIC.iter().any(|magic| body.starts_with(magic)) => FrameKind::Tiff,
+        _ if nifti::is_nifti(&body) => FrameKind::Nifti,
+        _ => return None,
+    };
+
+    let volume = read_blocking(move || match format {
+        FrameKind::Nifti => nifti::read(&body, max_len).map_err(|err| err.to_string()),
+        FrameKind::Tiff => images::read_tiff(&body, max_len).map_err(|err| err.to_string()),
+        _ => npy::read(&body, max_len).map_err(|err| err.to_string()),
+    });
+    Some(volume.await)
+}
+
+/// HTTP status that goes with a delivery report
+fn delivery_status(delivery: &Envelope) -> StatusCode {
+    match delivery {
+        Envelope::Delivery { delivered: true, .. } => StatusCode::OK,
+        Envelope::Delivery { reason: Some(DeliveryFailure::NoViewer), .. } => StatusCode::ACCEPTED,
+        Envelope::Delivery { reason: Some(DeliveryFailure::Oversize), .. } => StatusCode::PAYLOAD_TOO_LARGE,
+        Envelope::Delivery { reason: Some(DeliveryFailure::Malformed), .. } => StatusCode::BAD_REQUEST,
+        _ => StatusCode::SERVICE_UNAVAILABLE,
+    }
+}
+
+/// `POST /api/arrays/:hash`: store an array and push it to the viewers registered for `hash`
+pub async fn upload_array(
+    State(state): State<Arc<AppState>>,
+    Path(hash): Path<String>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> impl IntoResponse {
+    // The uploader is not a websocket connection, but needs an id that no viewer has
+    let who = state.connection_id();
+
+    if let Some(volume) = read_file(&headers, body.clone(), state.max_payload_size).await {
+        println!(">>> HTTP client sent file of {} bytes for hash `{}`", body.len(), hash);
+        let delivery = match volume {
+            Ok(volume) => publish_volume(&state, who, hash, volume).await,
+            Err(err) => Envelope::nack(Some(hash), DeliveryFailure::Malformed, err),
+        };
+        return (delivery_status(&delivery), Json(delivery));
+    }
+
+    let header = match array_header(hash.clone(), &headers) {
+        Ok(header) => header,
+        Err(err) => {
+            let nack = Envelope::nack(Some(hash), DeliveryFailure::Malformed, err);
+            return (StatusCode::BAD_REQUEST, Json(nack));
+        }
+    };
+
+    match header.byte_len() {
+        Ok(len) if len == body.len() as u64 => {}
+        Ok(len) => {
+            let err = format!("shape and dtype describe {len} bytes, but {} bytes were sent", body.len());
+            let nack = Envelope::nack(Some(hash), DeliveryFailure::Malformed, err);
+            return (StatusCode::BAD_REQUEST, Json(nack));
+        }
+        Err(err) => {
+            let nack = Envelope::nack(Some(hash), DeliveryFailure::Malformed, err);
+            return (StatusCode::BAD_REQUEST, Json(nack));
+        }
+    }
+
+    println!(">>> HTTP client sent {} bytes for hash `{}`", body.len(), hash);
+
+    publish_header(&state, who, header);
+    let delivery = relay_frame(&state, who, Frame::array(hash, body.to_vec())).await;
+    (delivery_status(&delivery), Json(delivery))
+}
+
+/// Body of a request to open a file on the disk of the server
+#[derive(Debug, Deserialize)]
+pub struct OpenRequest {
+    path: String,
+    series: Option<String>,
+}
+
+/// `POST /api/open/:hash`: read a file from the disk of the server, in one of the directories
+/// allowed with `--open-dir`, and push it to the viewers registered for `hash`
+pub async fn open_file(
+    State(state): State<Arc<AppState>>,
+    Path(hash): Path<String>,
+    Json(request): Json<OpenRequest>,
+) -> impl IntoResponse {
+    let who = state.connection_id();
+    let delivery = open_requested(&state, who, hash, request.path.into(), request.series).await;
+    (delivery_status(&delivery), Json(delivery))
+}
+
+/// Resolve the first range of a `Range` header against an array of `len` bytes, returning the
+/// inclusive start and end offsets. Multiple ranges are not supported, only the first is served.
+fn satisfiable_range(range: &headers::Range, len: u64) -> Option<(u64, u64)> {
+    let (start, end) = range.iter().next()?;
+    let (start, end) = match (start, end) {
+        (Bound::Included(start), Bound::Included(end)) => (start, end.min(len.checked_sub(1)?)),
+        (Bound::Included(start), Bound::Unbounded) => (start, len.checked_sub(1)?),
+
+        // `bytes=-n` asks for the last `n` bytes
+        (Bound::Unbounded, Bound::Included(n)) if n > 0 => (len.saturating_sub(n), len.checked_sub(1)?),
+        _ => return None,
+    };
+
+    (start <= end).then_some((start, end))
+}
+
+/// Query of `GET /api/arrays/:hash`
+#[derive(Debug, Deserialize)]
+pub struct LevelQuery {
+    /// Level of the pyramid to fetch, full resolution if not given
+    level: Option<usize>,
+}
+
+/// `GET /api/arrays/:hash`: the latest array sent for `hash`, or a level of its pyramid, with its
+/// shape and dtype in the response headers. Supports `Range` requests.
+pub async fn download_array(
+    State(state): State<Arc<AppState>>,
+    Path(hash): Path<String>,
+    Query(query): Query<LevelQuery>,
+    range: Option<TypedHeader<headers::Range>>,
+) -> impl IntoResponse {
+    let latest = state.latest.lock().unwrap().get(&hash).cloned().unwrap_or_default();
+    let level = query.level.unwrap_or(0);
+    let Some((meta, frame)) = latest.level(level) else {
+        return not_found(format!("no array for hash `{hash}` at level {level}"));
+    };
+
+    let shape = meta.shape.iter().map(|d| d.to_string()).collect::<Vec<_>>().join(",");
+    let builder = Response::builder()
+        .header(header::CONTENT_TYPE, "application/octet-stream")
+        .header(header::ACCEPT_RANGES, "bytes")
+        .header(SHAPE_HEADER, shape)
+        .header(DTYPE_HEADER, &meta.dtype);
+
+    ranged(builder, &frame.payload, range)
+}
+
+/// Respond with `data`, or the part of it asked for by a `Range` header
+fn ranged(
+    builder: axum::http::response::Builder,
+    data: &[u8],
+    range: Option<TypedHeader<headers::Range>>,
+) -> Response<BoxBody> {
+    let Some(TypedHeader(range)) = range else {
+        return builder
+            .status(StatusCode::OK)
+            .body(boxed(Body::from(data.to_vec())))
+            .unwrap();
+    };
+
+    let len = data.len() as u64;
+    match satisfiable_range(&range, len) {
+        Some((start, end)) => builder
+            .status(StatusCode::PARTIAL_CONTENT)
+            .header(header::CONTENT_RANGE, format!("bytes {start}-{end}/{len}"))
+            .body(boxed(Body::from(data[start as usize..=end as usize].to_vec())))
+            .unwrap(),
+        None => builder
+            .status(StatusCode::RANGE_NOT_SATISFIABLE)
+            .header(header::CONTENT_RANGE, format!("bytes */{len}"))
+            .body(boxed(Body::empty()))
+            .unwrap(),
+    }
+}
+
+/// `404 Not Found` response with a plain-text message
+fn not_found(message: String) -> Response<BoxBody> {
+    Response::builder()
+        .status(StatusCode::NOT_FOUND)
+        .body(boxed(Body::from(message)))
+        .unwrap()
+}
+
+/// `GET /api/zarr/:hash/*key`: a metadata document or chunk of the Zarr store opened for `hash`.
+/// Supports `Range` requests, e.g. for the shard indexes of Zarr v3.
+pub async fn zarr_key(
+    State(state): State<Arc<AppState>>,
+    Path((hash, key)): Path<(String, String)>,
+    range: Option<TypedHeader<headers::Range>>,
+) -> Response<BoxBody> {
+    let store = state.latest.lock().unwrap().get(&hash).and_then(|latest| latest.store.clone());
+    let Some(store) = store else {
+        return not_found(format!("no Zarr store for hash `{hash}`"));
+    };
+    let Some(path) = store.resolve(&key) else {
+        return not_found(format!("no key `{key}` in the Zarr store for hash `{hash}`"));
+    };
+
+    // Missing chunks are valid in Zarr, and hold only the fill value
+    let data = match tokio::fs::read(&path).await {
+        Ok(data) => data,
+        Err(_) => return not_found(format!("no key `{key}` in the Zarr store for hash `{hash}`")),
+    };
+
+    let name = key.rsplit('/').next().unwrap_or_default();
+    let content_type = match name {
+        ".zarray" | ".zattrs" | ".zgroup" | ".zmetadata" | "zarr.json" => "application/json",
+        _ => "application/octet-stream",
+    };
+    let builder = Response::builder()
+        .header(header::CONTENT_TYPE, content_type)
+        .header(header::ACCEPT_RANGES, "bytes");
+    ranged(builder, &data, range)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use headers::HeaderMapExt;
+
+    /// The range `value` of a `Range` header asks for of an array of `len` bytes
+    fn range(value: &str, len: u64) -> Option<(u64, u64)> {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::RANGE, value.parse().unwrap());
+        satisfiable_range(&headers.typed_get().unwrap(), len)
+    }
+
+    #[test]
+    fn resolves_ranges() {
+        assert_eq!(range("bytes=0-9", 100), Some((0, 9)));
+        assert_eq!(range("bytes=50-", 100), Some((50, 99)));
+        assert_eq!(range("bytes=90-200", 100), Some((90, 99)));
+    }
+
+    #[test]
+    fn resolves_suffix_ranges() {
+        assert_eq!(range("bytes=-10", 100), Some((90, 99)));
+        assert_eq!(range("bytes=-200", 100), Some((0, 99)));
+        assert_eq!(range("bytes=-0", 100), None);
+    }
+
+    #[test]
+    fn rejects_ranges_past_the_end() {
+        assert_eq!(range("bytes=100-", 100), None);
+        assert_eq!(range("bytes=150-160", 100), None);
+        assert_eq!(range("bytes=0-", 0), None);
+        assert_eq!(range("bytes=-10", 0), None);
+    }
+
+    #[test]
+    fn serves_the_first_of_multiple_ranges() {
+        assert_eq!(range("bytes=0-9, 20-29", 100), Some((0, 9)));
+        assert_eq!(range("bytes=-5, 0-9", 100), Some((95, 99)));
+    }
+}
@@ -0,0 +1,131 @@
+//! Conversion of arrays to data types viewers handle well
+//!
+//! Arrays arrive in whatever dtype NumPy computed them in, while viewers upload them to the GPU,
+//! which has no 64-bit or boolean textures and expects little-endian elements. When started
+//! with `--convert-dtypes`, the server converts such arrays before relaying them, and rewrites
+//! their header to match:
+//!
+//! ```not_rust
+//! float64          float32
+//! int64, uint64    int32, uint32, if every element fits
+//! bool             uint8, marked as a label map
+//! big-endian       little-endian
+//! ```
+
+use crate::dtype::{ByteOrder, DType};
+use crate::message::ArrayHeader;
+
+/// The dtype arrays of `dtype` are converted to, if any
+fn target(dtype: DType) -> Option<DType> {
+    match dtype {
+        DType::Float64 => Some(DType::Float32),
+        DType::Int64 => Some(DType::Int32),
+        DType::Uint64 => Some(DType::Uint32),
+        DType::Bool => Some(DType::Uint8),
+        _ => None,
+    }
+}
+
+/// Whether `value` can be stored in `dtype` without being clipped
+fn fits(dtype: DType, value: f64) -> bool {
+    match dtype {
+        DType::Int32 => (i32::MIN as f64..=i32::MAX as f64).contains(&value),
+        DType::Uint32 => (0.0..=u32::MAX as f64).contains(&value),
+        _ => true,
+    }
+}
+
+/// Whether the array described by `header` is converted
+pub fn applies(header: &ArrayHeader) -> bool {
+    header.byteorder == Some(ByteOrder::Big) || header.dtype.parse().ok().and_then(target).is_some()
+}
+
+/// Convert an array of `dtype` to little-endian elements of a dtype viewers handle well, and
+/// rewrite its header to match. Integers that do not all fit in 32 bits keep their dtype.
+pub fn convert(header: &mut ArrayHeader, dtype: DType, mut data: Vec<u8>) -> Vec<u8> {
+    let itemsize = dtype.itemsize();
+    if header.byteorder.take() == Some(ByteOrder::Big) {
+        for item in data.chunks_exact_mut(itemsize) {
+            item.reverse();
+        }
+    }
+
+    let Some(target) = target(dtype) else {
+        return data;
+    };
+    if !data.chunks_exact(itemsize).all(|item| fits(target, dtype.value(item))) {
+        return data;
+    }
+
+    let mut out = Vec::with_capacity(data.len() / itemsize * target.itemsize());
+    for item in data.chunks_exact(itemsize) {
+        target.push(&mut out, dtype.value(item));
+    }
+
+    // A mask stays a mask, so it is displayed, and downsampled, as one
+    if dtype == DType::Bool {
+        header.labels = true;
+    }
+    header.dtype = target.name().to_owned();
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn array(dtype: DType, len: usize) -> ArrayHeader {
+        ArrayHeader::new("abc".to_owned(), vec![len], dtype)
+    }
+
+    #[test]
+    fn narrows_integers_that_fit() {
+        let data: Vec<u8> = [-3i64, 0, i32::MAX as i64].iter().flat_map(|v| v.to_le_bytes()).collect();
+        let mut header = array(DType::Int64, 3);
+        let data = convert(&mut header, DType::Int64, data);
+        assert_eq!(header.dtype, "int32");
+        assert_eq!(data, [-3i32, 0, i32::MAX].iter().flat_map(|v| v.to_le_bytes()).collect::<Vec<u8>>());
+    }
+
+    #[test]
+    fn keeps_integers_out_of_range() {
+        let values: Vec<u8> = [1i64, i32::MIN as i64 - 1].iter().flat_map(|v| v.to_le_bytes()).collect();
+        let mut header = array(DType::Int64, 2);
+        assert_eq!(convert(&mut header, DType::Int64, values.clone()), values);
+        assert_eq!(header.dtype, "int64");
+
+        let values: Vec<u8> = [1u64, u32::MAX as u64 + 1].iter().flat_map(|v| v.to_le_bytes()).collect();
+        let mut header = array(DType::Uint64, 2);
+        assert_eq!(convert(&mut header, DType::Uint64, values.clone()), values);
+        assert_eq!(header.dtype, "uint64");
+    }
+
+    #[test]
+    fn swaps_big_endian_values_that_keep_their_range() {
+        let values: Vec<u8> = [1i64, i64::MAX].iter().flat_map(|v| v.to_be_bytes()).collect();
+        let mut header = array(DType::Int64, 2);
+        header.byteorder = Some(ByteOrder::Big);
+        assert!(applies(&header));
+
+        let data = convert(&mut header, DType::Int64, values);
+        assert_eq!((header.dtype.as_str(), header.byteorder), ("int64", None));
+        assert_eq!(data, [1i64, i64::MAX].iter().flat_map(|v| v.to_le_bytes()).collect::<Vec<u8>>());
+    }
+
+    #[test]
+    fn converts_floats_and_masks() {
+        let values: Vec<u8> = [0.5f64, -2.0].iter().flat_map(|v| v.to_be_bytes()).collect();
+        let mut header = array(DType::Float64, 2);
+        header.byteorder = Some(ByteOrder::Big);
+        let data = convert(&mut header, DType::Float64, values);
+        assert_eq!(header.dtype, "float32");
+        assert_eq!(data, [0.5f32, -2.0].iter().flat_map(|v| v.to_le_bytes()).collect::<Vec<u8>>());
+
+        let mut header = array(DType::Bool, 3);
+        assert_eq!(convert(&mut header, DType::Bool, vec![1, 0, 1]), [1, 0, 1]);
+        assert_eq!(header.dtype, "uint8");
+        assert!(header.labels);
+
+        assert!(!applies(&array(DType::Float32, 2)));
+    }
+}
@@ -0,0 +1,734 @@
+//! DICOM series
+//!
+//! A directory of DICOM files, as written by a scanner, is read into a 5D array of shape
+//! `(1, z, y, x, c)`. Files are grouped by their series, and the slices of a series are sorted
+//! by their position along the slice normal, falling back to the instance number. The rescale
+//! slope and intercept are applied, so the viewer shows e.g. Hounsfield units, and the voxel
+//! spacing, affine and default window are sent along in the header.
+//!
+//! Only uncompressed transfer syntaxes are supported: implicit and explicit VR little endian,
+//! explicit VR big endian and deflated explicit VR little endian.
+//!
+//! See https://dicom.nema.org/medical/dicom/current/output/html/part05.html
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use flate2::read::DeflateDecoder;
+
+use crate::dtype::DType;
+use crate::volume::{interleave, Volume};
+
+/// Magic bytes following the 128-byte preamble of every DICOM file
+const DICM: &[u8] = b"DICM";
+
+/// Transfer syntaxes with uncompressed pixel data
+const IMPLICIT_VR_LITTLE_ENDIAN: &str = "1.2.840.10008.1.2";
+const EXPLICIT_VR_LITTLE_ENDIAN: &str = "1.2.840.10008.1.2.1";
+const DEFLATED_EXPLICIT_VR_LITTLE_ENDIAN: &str = "1.2.840.10008.1.2.1.99";
+const EXPLICIT_VR_BIG_ENDIAN: &str = "1.2.840.10008.1.2.2";
+
+// Tags of the elements read from every file
+const TRANSFER_SYNTAX_UID: u32 = 0x0002_0010;
+const SERIES_DESCRIPTION: u32 = 0x0008_103e;
+const SLICE_THICKNESS: u32 = 0x0018_0050;
+const SPACING_BETWEEN_SLICES: u32 = 0x0018_0088;
+const SERIES_INSTANCE_UID: u32 = 0x0020_000e;
+const INSTANCE_NUMBER: u32 = 0x0020_0013;
+const IMAGE_POSITION_PATIENT: u32 = 0x0020_0032;
+const IMAGE_ORIENTATION_PATIENT: u32 = 0x0020_0037;
+const SAMPLES_PER_PIXEL: u32 = 0x0028_0002;
+const NUMBER_OF_FRAMES: u32 = 0x0028_0008;
+const PLANAR_CONFIGURATION: u32 = 0x0028_0006;
+const ROWS: u32 = 0x0028_0010;
+const COLUMNS: u32 = 0x0028_0011;
+const PIXEL_SPACING: u32 = 0x0028_0030;
+const BITS_ALLOCATED: u32 = 0x0028_0100;
+const BITS_STORED: u32 = 0x0028_0101;
+const PIXEL_REPRESENTATION: u32 = 0x0028_0103;
+const WINDOW_CENTER: u32 = 0x0028_1050;
+const WINDOW_WIDTH: u32 = 0x0028_1051;
+const RESCALE_INTERCEPT: u32 = 0x0028_1052;
+const RESCALE_SLOPE: u32 = 0x0028_1053;
+const PIXEL_DATA: u32 = 0x7fe0_0010;
+
+// Delimiters of sequences and their items
+const ITEM: u32 = 0xfffe_e000;
+const ITEM_END: u32 = 0xfffe_e00d;
+const SEQUENCE_END: u32 = 0xfffe_e0dd;
+const UNDEFINED_LENGTH: u32 = 0xffff_ffff;
+
+/// Explicit VRs whose length is stored in 4 bytes, after 2 reserved ones
+const LONG_VRS: &[&[u8]] = &[
+    b"OB", b"OD", b"OF", b"OL", b"OV", b"OW", b"SQ", b"SV", b"UC", b"UN", b"UR", b"UT", b"UV",
+];
+
+/// Reasons a DICOM series can be rejected
+#[derive(Debug)]
+pub enum DicomError {
+    Io(std::io::Error),
+    Truncated,
+    UnsupportedTransferSyntax(String),
+    UnsupportedPixelFormat { bits_allocated: u16, samples: u16 },
+    NoImages(PathBuf),
+    UnknownSeries(String),
+    InconsistentSeries(String),
+    InvalidDimensions { rows: usize, columns: usize, frames: usize },
+    TooLarge(u64),
+}
+
+impl fmt::Display for DicomError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DicomError::Io(err) => write!(f, "could not read DICOM file: {err}"),
+            DicomError::Truncated => write!(f, "DICOM file is truncated"),
+            DicomError::UnsupportedTransferSyntax(uid) => {
+                write!(f, "unsupported DICOM transfer syntax `{uid}`, only uncompressed data is supported")
+            }
+            DicomError::UnsupportedPixelFormat { bits_allocated, samples } => write!(
+                f,
+                "unsupported DICOM pixel format of {samples} samples of {bits_allocated} bits"
+            ),
+            DicomError::NoImages(path) => write!(f, "no DICOM images found in `{}`", path.display()),
+            DicomError::UnknownSeries(uid) => write!(f, "no DICOM series `{uid}`"),
+            DicomError::InconsistentSeries(uid) => {
+                write!(f, "slices of DICOM series `{uid}` differ in size or pixel format")
+            }
+            DicomError::InvalidDimensions { rows, columns, frames } => {
+                write!(f, "invalid DICOM image of {frames} frames of {rows}×{columns} pixels")
+            }
+            DicomError::TooLarge(max_len) => write!(f, "DICOM file exceeds the limit of {max_len} bytes"),
+        }
+    }
+}
+
+impl std::error::Error for DicomError {}
+
+impl From<std::io::Error> for DicomError {
+    fn from(err: std::io::Error) -> Self {
+        DicomError::Io(err)
+    }
+}
+
+/// The elements of a single DICOM file needed to build a volume
+#[derive(Debug, Clone, Default)]
+struct Slice {
+    series_uid: String,
+    description: String,
+    instance: i64,
+    position: Option<[f64; 3]>,
+    orientation: Option<[f64; 6]>,
+    rows: usize,
+    columns: usize,
+    frames: usize,
+    samples: u16,
+    planar: bool,
+    bits_allocated: u16,
+    bits_stored: u16,
+    signed: bool,
+    pixel_spacing: Option<[f64; 2]>,
+    thickness: Option<f64>,
+    spacing_between: Option<f64>,
+    slope: f64,
+    intercept: f64,
+    window: Option<(f64, f64)>,
+    pixels: Vec<u8>,
+}
+
+impl Slice {
+    /// Whether two slices can be stacked into one volume
+    fn matches(&self, other: &Slice) -> bool {
+        (self.rows, self.columns, self.samples, self.planar) == (other.rows, other.columns, other.samples, other.planar)
+            && (self.bits_allocated, self.bits_stored, self.signed) == (other.bits_allocated, other.bits_stored, other.signed)
+    }
+
+    /// Raw value of the element at `index`, with unused high bits masked off and sign-extended
+    fn raw(&self, index: usize) -> f64 {
+        let size = self.bits_allocated as usize / 8;
+        let mut bytes = [0; 4];
+        bytes[..size].copy_from_slice(&self.pixels[index * size..(index + 1) * size]);
+        let value = u32::from_le_bytes(bytes);
+
+        let bits = self.bits_stored.clamp(1, self.bits_allocated) as u32;
+        let value = if bits < 32 { value & ((1 << bits) - 1) } else { value };
+        if self.signed && bits < 32 && value >> (bits - 1) == 1 {
+            value as f64 - (1u64 << bits) as f64
+        } else if self.signed && bits == 32 {
+            value as i32 as f64
+        } else {
+            value as f64
+        }
+    }
+
+    /// Range of the raw values the pixel format can hold
+    fn raw_range(&self) -> (f64, f64) {
+        let bits = self.bits_stored.clamp(1, self.bits_allocated) as i32;
+        if self.signed {
+            (-(2f64.powi(bits - 1)), 2f64.powi(bits - 1) - 1.0)
+        } else {
+            (0.0, 2f64.powi(bits) - 1.0)
+        }
+    }
+}
+
+/// Reads the elements of a data set in a given transfer syntax
+struct Parser<'a> {
+    data: &'a [u8],
+    pos: usize,
+    explicit: bool,
+    big_endian: bool,
+}
+
+impl<'a> Parser<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], DicomError> {
+        let end = self.pos.checked_add(len).filter(|end| *end <= self.data.len());
+        let end = end.ok_or(DicomError::Truncated)?;
+        let bytes = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn u16(&mut self) -> Result<u16, DicomError> {
+        let bytes = self.take(2)?.try_into().unwrap();
+        Ok(if self.big_endian { u16::from_be_bytes(bytes) } else { u16::from_le_bytes(bytes) })
+    }
+
+    fn u32(&mut self) -> Result<u32, DicomError> {
+        let bytes = self.take(4)?.try_into().unwrap();
+        Ok(if self.big_endian { u32::from_be_bytes(bytes) } else { u32::from_le_bytes(bytes) })
+    }
+
+    fn tag(&mut self) -> Result<u32, DicomError> {
+        Ok((self.u16()? as u32) << 16 | self.u16()? as u32)
+    }
+
+    fn at_end(&self) -> bool {
+        self.pos >= self.data.len()
+    }
+
+    /// Group of the next element, without consuming it
+    fn peek_group(&self) -> Option<u16> {
+        let bytes = self.data.get(self.pos..self.pos + 2)?.try_into().unwrap();
+        Some(if self.big_endian { u16::from_be_bytes(bytes) } else { u16::from_le_bytes(bytes) })
+    }
+
+    /// The tag and value of the next element. Sequences of undefined length are skipped, and
+    /// returned with an empty value.
+    fn element(&mut self) -> Result<(u32, &'a [u8]), DicomError> {
+        let tag = self.tag()?;
+
+        // Items and delimiters never have a VR
+        let len = if self.explicit && tag >> 16 != 0xfffe {
+            let vr = self.take(2)?;
+            if LONG_VRS.contains(&vr) {
+                self.take(2)?;
+                self.u32()?
+            } else {
+                self.u16()? as u32
+            }
+        } else {
+            self.u32()?
+        };
+
+        if len == UNDEFINED_LENGTH {
+            // Pixel data of undefined length is encapsulated, i.e. compressed
+            if tag == PIXEL_DATA {
+                return Err(DicomError::UnsupportedTransferSyntax("encapsulated pixel data".to_owned()));
+            }
+            self.skip_sequence()?;
+            return Ok((tag, &[]));
+        }
+        Ok((tag, self.take(len as usize)?))
+    }
+
+    /// Skip the items of a sequence of undefined length, up to and including its delimiter
+    fn skip_sequence(&mut self) -> Result<(), DicomError> {
+        loop {
+            let tag = self.tag()?;
+            let len = self.u32()?;
+            match tag {
+                SEQUENCE_END => return Ok(()),
+                ITEM if len == UNDEFINED_LENGTH => while self.element()?.0 != ITEM_END {},
+                ITEM => {
+                    self.take(len as usize)?;
+                }
+                _ => return Err(DicomError::Truncated),
+            }
+        }
+    }
+
+    /// Value of an `US` element
+    fn us(&self, value: &[u8]) -> u16 {
+        match value.get(..2) {
+            Some(&[a, b]) if self.big_endian => u16::from_be_bytes([a, b]),
+            Some(&[a, b]) => u16::from_le_bytes([a, b]),
+            _ => 0,
+        }
+    }
+}
+
+/// Value of a string element, without padding
+fn text(value: &[u8]) -> String {
+    String::from_utf8_lossy(value).trim_matches(|c: char| c == ' ' || c == '\0').to_owned()
+}
+
+/// Values of a multi-valued `DS` or `IS` element
+fn numbers(value: &[u8]) -> Vec<f64> {
+    text(value).split('\\').filter_map(|v| v.trim().parse().ok()).collect()
+}
+
+/// Read the elements of a single DICOM file, returning `None` if it is not a DICOM image.
+/// Deflated data sets are not inflated beyond `max_len` bytes.
+fn read_file(data: &[u8], max_len: u64) -> Result<Option<Slice>, DicomError> {
+    if data.get(128..132) != Some(DICM) {
+        return Ok(None);
+    }
+
+    // The file meta information is always explicit VR little endian
+    let mut meta = Parser {
+        data,
+        pos: 132,
+        explicit: true,
+        big_endian: false,
+    };
+    let mut transfer_syntax = IMPLICIT_VR_LITTLE_ENDIAN.to_owned();
+    while meta.peek_group() == Some(0x0002) {
+        let (tag, value) = meta.element()?;
+        if tag == TRANSFER_SYNTAX_UID {
+            transfer_syntax = text(value);
+        }
+    }
+
+    let rest = &data[meta.pos..];
+    match transfer_syntax.as_str() {
+        IMPLICIT_VR_LITTLE_ENDIAN => read_dataset(rest, false, false),
+        EXPLICIT_VR_LITTLE_ENDIAN => read_dataset(rest, true, false),
+        EXPLICIT_VR_BIG_ENDIAN => read_dataset(rest, true, true),
+        DEFLATED_EXPLICIT_VR_LITTLE_ENDIAN => {
+            let mut inflated = Vec::new();
+            DeflateDecoder::new(rest).take(max_len + 1).read_to_end(&mut inflated)?;
+            if inflated.len() as u64 > max_len {
+                return Err(DicomError::TooLarge(max_len));
+            }
+            read_dataset(&inflated, true, false)
+        }
+        _ => Err(DicomError::UnsupportedTransferSyntax(transfer_syntax)),
+    }
+}
+
+/// Read the elements of a data set up to and including its pixel data
+fn read_dataset(data: &[u8], explicit: bool, big_endian: bool) -> Result<Option<Slice>, DicomError> {
+    let mut parser = Parser {
+        data,
+        pos: 0,
+        explicit,
+        big_endian,
+    };
+    let mut slice = Slice {
+        frames: 1,
+        samples: 1,
+        bits_allocated: 16,
+        slope: 1.0,
+        ..Default::default()
+    };
+    let mut pixels = None;
+    let mut window = (None, None);
+
+    while !parser.at_end() {
+        let (tag, value) = parser.element()?;
+        match tag {
+            SERIES_DESCRIPTION => slice.description = text(value),
+            SLICE_THICKNESS => slice.thickness = numbers(value).first().copied(),
+            SPACING_BETWEEN_SLICES => slice.spacing_between = numbers(value).first().copied(),
+            SERIES_INSTANCE_UID => slice.series_uid = text(value),
+            INSTANCE_NUMBER => slice.instance = numbers(value).first().copied().unwrap_or(0.0) as i64,
+            IMAGE_POSITION_PATIENT => slice.position = numbers(value).try_into().ok(),
+            IMAGE_ORIENTATION_PATIENT => slice.orientation = numbers(value).try_into().ok(),
+            SAMPLES_PER_PIXEL => slice.samples = parser.us(value),
+            PLANAR_CONFIGURATION => slice.planar = parser.us(value) == 1,
+            NUMBER_OF_FRAMES => slice.frames = numbers(value).first().copied().unwrap_or(1.0).max(1.0) as usize,
+            ROWS => slice.rows = parser.us(value) as usize,
+            COLUMNS => slice.columns = parser.us(value) as usize,
+            PIXEL_SPACING => slice.pixel_spacing = numbers(value).try_into().ok(),
+            BITS_ALLOCATED => slice.bits_allocated = parser.us(value),
+            BITS_STORED => slice.bits_stored = parser.us(value),
+            PIXEL_REPRESENTATION => slice.signed = parser.us(value) == 1,
+            WINDOW_CENTER => window.0 = numbers(value).first().copied(),
+            WINDOW_WIDTH => window.1 = numbers(value).first().copied(),
+            RESCALE_INTERCEPT => slice.intercept = numbers(value).first().copied().unwrap_or(0.0),
+            RESCALE_SLOPE => slice.slope = numbers(value).first().copied().unwrap_or(1.0),
+            PIXEL_DATA => {
+                pixels = Some(value);
+                break;
+            }
+            _ => {}
+        }
+    }
+
+    // Files without pixel data, such as a DICOMDIR, are not images
+    let Some(pixels) = pixels else {
+        return Ok(None);
+    };
+
+    if !matches!(slice.bits_allocated, 8 | 16 | 32) || !matches!(slice.samples, 1 | 3) {
+        return Err(DicomError::UnsupportedPixelFormat {
+            bits_allocated: slice.bits_allocated,
+            samples: slice.samples,
+        });
+    }
+    if slice.bits_stored == 0 {
+        slice.bits_stored = slice.bits_allocated;
+    }
+    if let (Some(center), Some(width)) = window {
+        slice.window = Some((center, width));
+    }
+
+    // The dimensions are read from the file, so their product can overflow
+    let itemsize = slice.bits_allocated as usize / 8;
+    let invalid = || DicomError::InvalidDimensions {
+        rows: slice.rows,
+        columns: slice.columns,
+        frames: slice.frames,
+    };
+    if slice.rows == 0 || slice.columns == 0 {
+        return Err(invalid());
+    }
+    let len = [slice.rows, slice.columns, slice.frames, slice.samples as usize]
+        .iter()
+        .try_fold(itemsize, |len, dim| len.checked_mul(*dim))
+        .ok_or_else(invalid)?;
+    let mut pixels = pixels.get(..len).ok_or(DicomError::Truncated)?.to_vec();
+    if big_endian && itemsize > 1 {
+        pixels.chunks_exact_mut(itemsize).for_each(|item| item.reverse());
+    }
+
+    // Color planes are interleaved into the samples of each pixel, frame by frame
+    if slice.samples > 1 && slice.planar {
+        let frame_len = len / slice.frames;
+        pixels = pixels
+            .chunks_exact(frame_len)
+            .flat_map(|frame| interleave(frame, slice.samples as usize, itemsize))
+            .collect();
+    }
+    slice.pixels = pixels;
+
+    Ok(Some(slice))
+}
+
+/// Every file below `dir`, or `dir` itself if it is a file. Symbolic links to directories are
+/// not followed, since they can form a cycle.
+fn files(dir: &Path) -> std::io::Result<Vec<PathBuf>> {
+    if dir.is_file() {
+        return Ok(vec![dir.to_owned()]);
+    }
+
+    let mut files = Vec::new();
+    let mut dirs = vec![dir.to_owned()];
+    while let Some(dir) = dirs.pop() {
+        for entry in std::fs::read_dir(dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            if entry.file_type()?.is_dir() {
+                dirs.push(path);
+            } else if path.is_file() {
+                files.push(path);
+            }
+        }
+    }
+    Ok(files)
+}
+
+fn cross(a: [f64; 3], b: [f64; 3]) -> [f64; 3] {
+    [a[1] * b[2] - a[2] * b[1], a[2] * b[0] - a[0] * b[2], a[0] * b[1] - a[1] * b[0]]
+}
+
+fn dot(a: [f64; 3], b: [f64; 3]) -> f64 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+/// Data type that holds the rescaled values of all slices without loss
+fn rescaled_dtype(slices: &[Slice]) -> DType {
+    let first = &slices[0];
+    let rescaled = slices.iter().any(|s| s.slope != 1.0 || s.intercept != 0.0);
+    if !rescaled {
+        return match (first.bits_allocated, first.signed) {
+            (8, false) => DType::Uint8,
+            (8, true) => DType::Int8,
+            (16, false) => DType::Uint16,
+            (16, true) => DType::Int16,
+            (_, false) => DType::Uint32,
+            (_, true) => DType::Int32,
+        };
+    }
+
+    if slices.iter().any(|s| s.slope.fract() != 0.0 || s.intercept.fract() != 0.0) {
+        return DType::Float32;
+    }
+
+    let (low, high) = first.raw_range();
+    let (min, max) = slices.iter().fold((f64::MAX, f64::MIN), |(min, max), s| {
+        let (a, b) = (low * s.slope + s.intercept, high * s.slope + s.intercept);
+        (min.min(a).min(b), max.max(a).max(b))
+    });
+    if min >= i16::MIN as f64 && max <= i16::MAX as f64 {
+        DType::Int16
+    } else if min >= i32::MIN as f64 && max <= i32::MAX as f64 {
+        DType::Int32
+    } else {
+        DType::Float32
+    }
+}
+
+/// Stack the slices of a series into a volume
+fn stack(uid: &str, mut slices: Vec<Slice>) -> Result<Volume, DicomError> {
+    if slices.iter().any(|s| !s.matches(&slices[0])) {
+        return Err(DicomError::InconsistentSeries(uid.to_owned()));
+    }
+
+    // Slices are sorted along the normal of the first one, if all have a position
+    let orientation = slices[0].orientation;
+    let normal = orientation.map(|o| cross([o[0], o[1], o[2]], [o[3], o[4], o[5]]));
+    let positions: Option<Vec<[f64; 3]>> = slices.iter().map(|s| s.position).collect();
+    match (normal, positions) {
+        (Some(normal), Some(_)) => slices.sort_by(|a, b| {
+            dot(a.position.unwrap(), normal).total_cmp(&dot(b.position.unwrap(), normal))
+        }),
+        _ => slices.sort_by_key(|s| s.instance),
+    }
+
+    let first = &slices[0];
+    let (rows, columns, samples) = (first.rows, first.columns, first.samples as usize);
+    let depth: usize = slices.iter().map(|s| s.frames).sum();
+
+    let dtype = rescaled_dtype(&slices);
+    let count = |slice: &Slice| slice.pixels.len() / (slice.bits_allocated as usize / 8);
+    let mut data = Vec::with_capacity(slices.iter().map(count).sum::<usize>() * dtype.itemsize());
+    for slice in &slices {
+        for index in 0..count(slice) {
+            dtype.push(&mut data, slice.raw(index) * slice.slope + slice.intercept);
+        }
+    }
+
+    // Distance between slices, from their positions or else as stated in the files
+    let last = &slices[slices.len() - 1];
+    let step = match (normal, first.position, last.position) {
+        (Some(normal), Some(a), Some(b)) if slices.len() > 1 => {
+            Some([b[0] - a[0], b[1] - a[1], b[2] - a[2]].map(|d| d / (slices.len() - 1) as f64))
+                .filter(|step| dot(*step, normal).abs() > 0.0)
+        }
+        _ => None,
+    };
+    let dz = match step {
+        Some(step) => dot(step, step).sqrt(),
+        None => first.spacing_between.or(first.thickness).filter(|d| *d > 0.0).unwrap_or(1.0),
+    };
+    let [dy, dx] = first.pixel_spacing.unwrap_or([1.0, 1.0]);
+
+    // DICOM patient coordinates are LPS, the affine maps to RAS
+    let affine = match (orientation, normal) {
+        (Some(o), Some(normal)) => {
+            let origin = first.position.unwrap_or_default();
+            let step = step.unwrap_or(normal.map(|n| n * dz));
+            let mut affine = [[0.0, 0.0, 0.0, 1.0]; 4];
+            for axis in 0..3 {
+                let sign = if axis < 2 { -1.0 } else { 1.0 };
+                affine[axis] = [o[axis] * dx, o[3 + axis] * dy, step[axis], origin[axis]].map(|v| v * sign);
+            }
+            affine
+        }
+        _ => [[dx, 0.0, 0.0, 0.0], [0.0, dy, 0.0, 0.0], [0.0, 0.0, dz, 0.0], [0.0, 0.0, 0.0, 1.0]],
+    };
+
+    let mut volume = Volume::new(vec![1, depth, rows, columns, samples], dtype, data);
+    volume.spacing = Some(vec![1.0, dz, dy, dx, 1.0]);
+    volume.affine = Some(affine);
+    volume.window = first.window;
+    Ok(volume)
+}
+
+/// Read the DICOM files below `dir` and stack the slices of one series into a volume: the one
+/// with the given series instance UID, or else the one with the most slices. Deflated files
+/// are not inflated beyond `max_len` bytes.
+///
+/// Exports often hold files that can not be read, such as a compressed localizer or a truncated
+/// file. These are skipped, and only fail the series if none of the other files are DICOM
+/// images.
+pub fn read_series(dir: &Path, series: Option<&str>, max_len: u64) -> Result<Volume, DicomError> {
+    let mut by_series: BTreeMap<String, Vec<Slice>> = BTreeMap::new();
+    let mut skipped = Vec::new();
+    for path in files(dir)? {
+        let slice = std::fs::read(&path).map_err(DicomError::from).and_then(|data| read_file(&data, max_len));
+        match slice {
+            Ok(Some(slice)) => by_series.entry(slice.series_uid.clone()).or_default().push(slice),
+            Ok(None) => {}
+            Err(err) => {
+                println!("--- skipping `{}`: {}", path.display(), err);
+                skipped.push(err);
+            }
+        }
+    }
+    if by_series.is_empty() && !skipped.is_empty() {
+        return Err(skipped.swap_remove(0));
+    }
+
+    let (uid, slices) = match series {
+        Some(uid) => by_series
+            .remove_entry(uid)
+            .ok_or_else(|| DicomError::UnknownSeries(uid.to_owned()))?,
+        None => {
+            let uid = by_series
+                .iter()
+                .max_by_key(|(_, slices)| slices.len())
+                .map(|(uid, _)| uid.clone())
+                .ok_or_else(|| DicomError::NoImages(dir.to_owned()))?;
+            by_series.remove_entry(&uid).unwrap()
+        }
+    };
+
+    println!(
+        "--- reading DICOM series `{}` ({}) of {} files, skipping {} other series and {} unreadable files",
+        uid,
+        slices[0].description,
+        slices.len(),
+        by_series.len(),
+        skipped.len()
+    );
+    stack(&uid, slices)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAX_LEN: u64 = 1 << 20;
+
+    /// An explicit VR little endian element
+    fn element(tag: u32, vr: &[u8; 2], value: &[u8]) -> Vec<u8> {
+        let mut out = ((tag >> 16) as u16).to_le_bytes().to_vec();
+        out.extend_from_slice(&(tag as u16).to_le_bytes());
+        out.extend_from_slice(vr);
+        if LONG_VRS.contains(&&vr[..]) {
+            out.extend_from_slice(&[0, 0]);
+            out.extend_from_slice(&(value.len() as u32).to_le_bytes());
+        } else {
+            out.extend_from_slice(&(value.len() as u16).to_le_bytes());
+        }
+        out.extend_from_slice(value);
+        out
+    }
+
+    fn us(tag: u32, value: u16) -> Vec<u8> {
+        element(tag, b"US", &value.to_le_bytes())
+    }
+
+    /// An explicit VR little endian file of a `rows`×`columns` image with the given elements
+    fn file(rows: u16, columns: u16, elements: &[Vec<u8>], pixels: &[u8]) -> Vec<u8> {
+        let mut data = vec![0; 128];
+        data.extend_from_slice(DICM);
+        data.extend(element(TRANSFER_SYNTAX_UID, b"UI", EXPLICIT_VR_LITTLE_ENDIAN.as_bytes()));
+        data.extend(element(SERIES_INSTANCE_UID, b"UI", b"1.2.3"));
+        data.extend(us(ROWS, rows));
+        data.extend(us(COLUMNS, columns));
+        elements.iter().for_each(|element| data.extend_from_slice(element));
+        data.extend(element(PIXEL_DATA, b"OW", pixels));
+        data
+    }
+
+    /// A 2×2 slice of 16-bit pixels that are all `value`, at `z` along its normal, if given
+    fn slice(instance: i64, z: Option<f64>, value: u16) -> Slice {
+        let mut elements = vec![element(INSTANCE_NUMBER, b"IS", instance.to_string().as_bytes())];
+        if let Some(z) = z {
+            elements.push(element(IMAGE_POSITION_PATIENT, b"DS", format!("0\\0\\{z}").as_bytes()));
+            elements.push(element(IMAGE_ORIENTATION_PATIENT, b"DS", b"1\\0\\0\\0\\1\\0"));
+        }
+        let data = file(2, 2, &elements, &value.to_le_bytes().repeat(4));
+        read_file(&data, MAX_LEN).unwrap().unwrap()
+    }
+
+    /// The value of the first pixel of every slice of a 2×2 `uint16` volume
+    fn first_pixels(volume: &Volume) -> Vec<u16> {
+        volume.data.chunks_exact(8).map(|slice| u16::from_le_bytes([slice[0], slice[1]])).collect()
+    }
+
+    #[test]
+    fn sorts_slices_by_position() {
+        let slices = vec![slice(1, Some(10.0), 10), slice(2, Some(0.0), 0), slice(3, Some(5.0), 5)];
+        let volume = stack("1.2.3", slices).unwrap();
+        assert_eq!((volume.shape.as_slice(), volume.dtype), (&[1, 3, 2, 2, 1][..], DType::Uint16));
+        assert_eq!(first_pixels(&volume), [0, 5, 10]);
+        assert_eq!(volume.spacing, Some(vec![1.0, 5.0, 1.0, 1.0, 1.0]));
+    }
+
+    #[test]
+    fn sorts_slices_without_position_by_instance() {
+        let slices = vec![slice(3, None, 30), slice(1, None, 10), slice(2, None, 20)];
+        let volume = stack("1.2.3", slices).unwrap();
+        assert_eq!(first_pixels(&volume), [10, 20, 30]);
+    }
+
+    #[test]
+    fn rescales_pixels() {
+        let elements = [
+            us(BITS_STORED, 12),
+            us(PIXEL_REPRESENTATION, 1),
+            element(RESCALE_SLOPE, b"DS", b"2"),
+            element(RESCALE_INTERCEPT, b"DS", b"-1024"),
+        ];
+        let pixels: Vec<u8> = [0i16, 1, -1, 512].iter().flat_map(|v| v.to_le_bytes()).collect();
+        let slice = read_file(&file(2, 2, &elements, &pixels), MAX_LEN).unwrap().unwrap();
+        let volume = stack("1.2.3", vec![slice]).unwrap();
+        assert_eq!(volume.dtype, DType::Int16);
+        let values: Vec<i16> = volume.data.chunks_exact(2).map(|v| i16::from_le_bytes([v[0], v[1]])).collect();
+        assert_eq!(values, [-1024, -1022, -1026, 0]);
+    }
+
+    #[test]
+    fn rejects_truncated_files() {
+        // Fewer pixels than the image holds
+        let data = file(3, 2, &[], &[0; 8]);
+        assert!(matches!(read_file(&data, MAX_LEN), Err(DicomError::Truncated)));
+
+        // An element that runs past the end of the file
+        let data = file(2, 2, &[], &[0; 8]);
+        assert!(matches!(read_file(&data[..data.len() - 3], MAX_LEN), Err(DicomError::Truncated)));
+    }
+
+    #[test]
+    fn rejects_zero_and_overflowing_dimensions() {
+        let invalid = |data: Vec<u8>| matches!(read_file(&data, MAX_LEN), Err(DicomError::InvalidDimensions { .. }));
+
+        assert!(invalid(file(0, 2, &[], &[0; 8])));
+        assert!(invalid(file(2, 0, &[], &[0; 8])));
+
+        // Color planes of an empty image
+        let planar = [us(SAMPLES_PER_PIXEL, 3), us(PLANAR_CONFIGURATION, 1), us(BITS_ALLOCATED, 8)];
+        assert!(invalid(file(0, 0, &planar, &[0; 12])));
+
+        let frames = [element(NUMBER_OF_FRAMES, b"IS", b"1e30")];
+        assert!(invalid(file(2, 2, &frames, &[0; 8])));
+    }
+
+    #[test]
+    fn interleaves_color_planes() {
+        let elements = [us(SAMPLES_PER_PIXEL, 3), us(PLANAR_CONFIGURATION, 1), us(BITS_ALLOCATED, 8)];
+        let planes = [1, 2, 10, 20, 100, 200];
+        let slice = read_file(&file(1, 2, &elements, &planes), MAX_LEN).unwrap().unwrap();
+        assert_eq!(slice.pixels, [1, 10, 100, 2, 20, 200]);
+    }
+
+    #[test]
+    fn skips_unreadable_files_of_a_series() {
+        let dir = std::env::temp_dir().join(format!("tunnelvision-dicom-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let good = file(2, 2, &[], &[0; 8]);
+        std::fs::write(dir.join("good.dcm"), &good).unwrap();
+        std::fs::write(dir.join("truncated.dcm"), &good[..good.len() - 3]).unwrap();
+        std::fs::write(dir.join("notes.txt"), b"not a DICOM file").unwrap();
+
+        let volume = read_series(&dir, None, MAX_LEN);
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(volume.unwrap().shape, [1, 1, 2, 2, 1]);
+    }
+}
@@ -0,0 +1,181 @@
+//! Data types
+//!
+//! Arrays are described by the NumPy name of their data type, e.g. `uint16` or `float32`.
+
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+/// The data types the server knows the layout of
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DType {
+    Bool,
+    Int8,
+    Uint8,
+    Int16,
+    Uint16,
+    Int32,
+    Uint32,
+    Int64,
+    Uint64,
+    Float16,
+    Float32,
+    Float64,
+}
+
+impl DType {
+    /// NumPy name of the data type
+    pub fn name(self) -> &'static str {
+        match self {
+            DType::Bool => "bool",
+            DType::Int8 => "int8",
+            DType::Uint8 => "uint8",
+            DType::Int16 => "int16",
+            DType::Uint16 => "uint16",
+            DType::Int32 => "int32",
+            DType::Uint32 => "uint32",
+            DType::Int64 => "int64",
+            DType::Uint64 => "uint64",
+            DType::Float16 => "float16",
+            DType::Float32 => "float32",
+            DType::Float64 => "float64",
+        }
+    }
+
+    /// Size of a single element in bytes
+    pub fn itemsize(self) -> usize {
+        match self {
+            DType::Bool | DType::Int8 | DType::Uint8 => 1,
+            DType::Int16 | DType::Uint16 | DType::Float16 => 2,
+            DType::Int32 | DType::Uint32 | DType::Float32 => 4,
+            DType::Int64 | DType::Uint64 | DType::Float64 => 8,
+        }
+    }
+
+    /// Value of a single little-endian element, converted to a float
+    pub fn value(self, item: &[u8]) -> f64 {
+        let u64_bytes = || -> [u8; 8] { item[..8].try_into().unwrap() };
+        match self {
+            DType::Bool => (item[0] != 0) as u8 as f64,
+            DType::Int8 => item[0] as i8 as f64,
+            DType::Uint8 => item[0] as f64,
+            DType::Int16 => i16::from_le_bytes([item[0], item[1]]) as f64,
+            DType::Uint16 => u16::from_le_bytes([item[0], item[1]]) as f64,
+            DType::Int32 => i32::from_le_bytes([item[0], item[1], item[2], item[3]]) as f64,
+            DType::Uint32 => u32::from_le_bytes([item[0], item[1], item[2], item[3]]) as f64,
+            DType::Int64 => i64::from_le_bytes(u64_bytes()) as f64,
+            DType::Uint64 => u64::from_le_bytes(u64_bytes()) as f64,
+            DType::Float16 => f16_to_f64(u16::from_le_bytes([item[0], item[1]])),
+            DType::Float32 => f32::from_le_bytes([item[0], item[1], item[2], item[3]]) as f64,
+            DType::Float64 => f64::from_le_bytes(u64_bytes()),
+        }
+    }
+
+    /// Append `value` to `out` as a single little-endian element, saturating at the limits of
+    /// integer types
+    pub fn push(self, out: &mut Vec<u8>, value: f64) {
+        match self {
+            DType::Bool => out.push((value != 0.0) as u8),
+            DType::Int8 => out.extend((value as i8).to_le_bytes()),
+            DType::Uint8 => out.push(value as u8),
+            DType::Int16 => out.extend((value as i16).to_le_bytes()),
+            DType::Uint16 => out.extend((value as u16).to_le_bytes()),
+            DType::Int32 => out.extend((value as i32).to_le_bytes()),
+            DType::Uint32 => out.extend((value as u32).to_le_bytes()),
+            DType::Int64 => out.extend((value as i64).to_le_bytes()),
+            DType::Uint64 => out.extend((value as u64).to_le_bytes()),
+            DType::Float16 => out.extend(f64_to_f16(value).to_le_bytes()),
+            DType::Float32 => out.extend((value as f32).to_le_bytes()),
+            DType::Float64 => out.extend(value.to_le_bytes()),
+        }
+    }
+}
+
+/// Order of the bytes within every element of an array, as in `sys.byteorder`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ByteOrder {
+    Little,
+    Big,
+}
+
+/// Decode an IEEE 754 half-precision float
+fn f16_to_f64(bits: u16) -> f64 {
+    let sign = if bits >> 15 == 1 { -1.0 } else { 1.0 };
+    let exponent = ((bits >> 10) & 0x1f) as i32;
+    let fraction = (bits & 0x3ff) as f64;
+    match exponent {
+        0 => sign * fraction * 2f64.powi(-24),
+        0x1f if fraction == 0.0 => sign * f64::INFINITY,
+        0x1f => f64::NAN,
+        _ => sign * (1.0 + fraction / 1024.0) * 2f64.powi(exponent - 15),
+    }
+}
+
+/// Encode an IEEE 754 half-precision float, rounding to the nearest representable value
+fn f64_to_f16(value: f64) -> u16 {
+    let sign = if value.is_sign_negative() { 0x8000 } else { 0 };
+    let magnitude = value.abs();
+    if value.is_nan() {
+        return 0x7e00;
+    }
+    if magnitude >= 65520.0 {
+        return sign | 0x7c00;
+    }
+    if magnitude < 2f64.powi(-14) {
+        // Subnormal, in units of 2^-24
+        return sign | (magnitude * 2f64.powi(24)).round_ties_even() as u16;
+    }
+
+    let mut exponent = magnitude.log2().floor() as i32;
+    if magnitude / 2f64.powi(exponent) >= 2.0 {
+        exponent += 1;
+    } else if magnitude / 2f64.powi(exponent) < 1.0 {
+        exponent -= 1;
+    }
+    let fraction = (magnitude / 2f64.powi(exponent) - 1.0) * 1024.0;
+    let bits = (((exponent + 15) as u16) << 10) + fraction.round_ties_even() as u16;
+    sign | bits
+}
+
+/// Returned when a data type name is not known to the server
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnknownDType(pub String);
+
+impl fmt::Display for UnknownDType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "unknown dtype `{}`", self.0)
+    }
+}
+
+impl std::error::Error for UnknownDType {}
+
+impl FromStr for DType {
+    type Err = UnknownDType;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let dtype = match s {
+            "bool" => DType::Bool,
+            "int8" => DType::Int8,
+            "uint8" => DType::Uint8,
+            "int16" => DType::Int16,
+            "uint16" => DType::Uint16,
+            "int32" => DType::Int32,
+            "uint32" => DType::Uint32,
+            "int64" => DType::Int64,
+            "uint64" => DType::Uint64,
+            "float16" => DType::Float16,
+            "float32" => DType::Float32,
+            "float64" => DType::Float64,
+            other => return Err(UnknownDType(other.to_owned())),
+        };
+        Ok(dtype)
+    }
+}
+
+impl fmt::Display for DType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
@@ -0,0 +1,446 @@
+//! Binary frame format
+//!
+//! Every binary websocket message sent to the server starts with a fixed-size header,
+//! followed by the hash of the receiving client and the payload itself. All integers are
+//! little-endian.
+//!
+//! ```not_rust
+//! offset  size  field
+//! 0       4     magic, always `TVIS`
+//! 4       1     protocol version
+//! 5       1     message kind
+//! 6       2     flags
+//! 8       2     hash length in bytes
+//! 10      8     payload length in bytes
+//! 18      n     hash (UTF-8)
+//! 18 + n  m     payload
+//! ```
+//!
+//! A payload compressed with zstd or LZ4 is flagged with bit 0 or bit 1 of the flags, in which
+//! case the payload length is that of the compressed payload. See [`crate::compression`].
+//!
+//! The payload of a chunk frame, which carries part of an array sent as a chunked upload,
+//! starts with a header of its own:
+//!
+//! ```not_rust
+//! offset  size  field
+//! 0       2     upload id length in bytes
+//! 2       k     upload id (UTF-8)
+//! 2 + k   8     offset of the chunk within the array
+//! 10 + k  ...   chunk data
+//! ```
+
+use std::fmt;
+
+use crate::compression::{Codec, Encodings};
+
+/// Magic bytes that start every binary frame
+pub const MAGIC: [u8; 4] = *b"TVIS";
+
+/// Current version of the binary protocol
+pub const VERSION: u8 = 1;
+
+/// Size of the fixed part of the header, i.e. without the hash
+pub const HEADER_LEN: usize = 18;
+
+/// The kind of payload carried by a frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameKind {
+    /// Raw array data, described by a preceding JSON header message
+    Array,
+    /// Part of an array, announced by a preceding JSON `upload_start` message
+    Chunk,
+    /// An array saved with `np.save`, no JSON header needed
+    Npy,
+    /// An archive saved with `np.savez`, of which the first array is relayed
+    Npz,
+    /// A NIfTI-1 volume, optionally gzipped
+    Nifti,
+    /// A multi-page TIFF file
+    Tiff,
+}
+
+impl TryFrom<u8> for FrameKind {
+    type Error = FrameError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(FrameKind::Array),
+            1 => Ok(FrameKind::Chunk),
+            2 => Ok(FrameKind::Npy),
+            3 => Ok(FrameKind::Npz),
+            4 => Ok(FrameKind::Nifti),
+            5 => Ok(FrameKind::Tiff),
+            other => Err(FrameError::UnknownKind(other)),
+        }
+    }
+}
+
+/// Optional flags, stored as a bit set
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FrameFlags(u16);
+
+impl FrameFlags {
+    /// The payload is compressed with zstd
+    pub const ZSTD: u16 = 0x0001;
+
+    /// The payload is compressed with LZ4
+    pub const LZ4: u16 = 0x0002;
+
+    /// All flags known to this version of the protocol
+    const KNOWN: u16 = Self::ZSTD | Self::LZ4;
+
+    /// The codec the payload is compressed with, if any
+    pub fn compression(self) -> Option<Codec> {
+        if self.0 & Self::ZSTD != 0 {
+            Some(Codec::Zstd)
+        } else if self.0 & Self::LZ4 != 0 {
+            Some(Codec::Lz4)
+        } else {
+            None
+        }
+    }
+}
+
+impl TryFrom<u16> for FrameFlags {
+    type Error = FrameError;
+
+    fn try_from(value: u16) -> Result<Self, Self::Error> {
+        if value & !Self::KNOWN != 0 {
+            return Err(FrameError::UnknownFlags(value & !Self::KNOWN));
+        }
+        if value & Self::ZSTD != 0 && value & Self::LZ4 != 0 {
+            return Err(FrameError::ConflictingFlags(value));
+        }
+        Ok(FrameFlags(value))
+    }
+}
+
+/// Parsed header of a binary frame
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FrameHeader {
+    pub version: u8,
+    pub kind: FrameKind,
+    pub flags: FrameFlags,
+    pub hash: String,
+    pub payload_len: u64,
+}
+
+/// Header at the start of the payload of a [`FrameKind::Chunk`] frame
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChunkHeader {
+    pub upload_id: String,
+    pub offset: u64,
+}
+
+/// A complete binary frame: the header and the payload it describes
+#[derive(Debug, Clone)]
+pub struct Frame {
+    pub header: FrameHeader,
+    pub payload: Vec<u8>,
+
+    /// Compressed forms of the payload, for viewers that negotiated compression
+    pub encodings: Encodings,
+}
+
+/// Reasons a binary frame can be rejected
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FrameError {
+    TooShort(usize),
+    BadMagic([u8; 4]),
+    UnsupportedVersion(u8),
+    UnknownKind(u8),
+    UnknownFlags(u16),
+    ConflictingFlags(u16),
+    EmptyHash,
+    InvalidHash,
+    EmptyUploadId,
+    InvalidUploadId,
+    LengthMismatch { expected: u64, actual: u64 },
+    Decompression(String),
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::TooShort(len) => write!(f, "frame of {len} bytes is too short"),
+            FrameError::BadMagic(magic) => write!(f, "bad magic bytes {magic:?}"),
+            FrameError::UnsupportedVersion(v) => write!(f, "unsupported protocol version {v}"),
+            FrameError::UnknownKind(k) => write!(f, "unknown message kind {k}"),
+            FrameError::UnknownFlags(bits) => write!(f, "unknown flags {bits:#06x}"),
+            FrameError::ConflictingFlags(bits) => {
+                write!(f, "flags {bits:#06x} select more than one compression codec")
+            }
+            FrameError::EmptyHash => write!(f, "hash is empty"),
+            FrameError::InvalidHash => write!(f, "hash is not valid UTF-8"),
+            FrameError::EmptyUploadId => write!(f, "upload id is empty"),
+            FrameError::InvalidUploadId => write!(f, "upload id is not valid UTF-8"),
+            FrameError::LengthMismatch { expected, actual } => write!(
+                f,
+                "header announces {expected} payload bytes, but frame carries {actual}"
+            ),
+            FrameError::Decompression(err) => write!(f, "could not decompress payload: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+impl FrameHeader {
+    /// Parse the header at the start of `data`, returning it together with the offset at
+    /// which the payload starts.
+    pub fn parse(data: &[u8]) -> Result<(Self, usize), FrameError> {
+        if data.len() < HEADER_LEN {
+            return Err(FrameError::TooShort(data.len()));
+        }
+
+        let magic: [u8; 4] = data[0..4].try_into().unwrap();
+        if magic != MAGIC {
+            return Err(FrameError::BadMagic(magic));
+        }
+
+        let version = data[4];
+        if version != VERSION {
+            return Err(FrameError::UnsupportedVersion(version));
+        }
+
+        let kind = FrameKind::try_from(data[5])?;
+        let flags = FrameFlags::try_from(u16::from_le_bytes([data[6], data[7]]))?;
+        let hash_len = u16::from_le_bytes([data[8], data[9]]) as usize;
+        let payload_len = u64::from_le_bytes(data[10..18].try_into().unwrap());
+
+        if hash_len == 0 {
+            return Err(FrameError::EmptyHash);
+        }
+
+        let offset = HEADER_LEN + hash_len;
+        if data.len() < offset {
+            return Err(FrameError::TooShort(data.len()));
+        }
+
+        let hash = std::str::from_utf8(&data[HEADER_LEN..offset])
+            .map_err(|_| FrameError::InvalidHash)?
+            .to_owned();
+
+        Ok((
+            FrameHeader {
+                version,
+                kind,
+                flags,
+                hash,
+                payload_len,
+            },
+            offset,
+        ))
+    }
+}
+
+impl ChunkHeader {
+    /// Parse the header at the start of a chunk payload, returning it together with the offset
+    /// at which the chunk data starts.
+    pub fn parse(payload: &[u8]) -> Result<(Self, usize), FrameError> {
+        if payload.len() < 2 {
+            return Err(FrameError::TooShort(payload.len()));
+        }
+
+        let id_len = u16::from_le_bytes([payload[0], payload[1]]) as usize;
+        if id_len == 0 {
+            return Err(FrameError::EmptyUploadId);
+        }
+
+        let data_offset = 2 + id_len + 8;
+        if payload.len() < data_offset {
+            return Err(FrameError::TooShort(payload.len()));
+        }
+
+        let upload_id = std::str::from_utf8(&payload[2..2 + id_len])
+            .map_err(|_| FrameError::InvalidUploadId)?
+            .to_owned();
+        let offset = u64::from_le_bytes(payload[2 + id_len..data_offset].try_into().unwrap());
+
+        Ok((ChunkHeader { upload_id, offset }, data_offset))
+    }
+}
+
+impl Frame {
+    /// Create a frame carrying a complete array for `hash`
+    pub fn array(hash: String, payload: Vec<u8>) -> Self {
+        Frame {
+            header: FrameHeader {
+                version: VERSION,
+                kind: FrameKind::Array,
+                flags: FrameFlags::default(),
+                hash,
+                payload_len: payload.len() as u64,
+            },
+            payload,
+            encodings: Encodings::default(),
+        }
+    }
+
+    /// Parse a complete frame, checking that the payload length matches the header.
+    pub fn parse(mut data: Vec<u8>) -> Result<Self, FrameError> {
+        let (header, offset) = FrameHeader::parse(&data)?;
+
+        let actual = (data.len() - offset) as u64;
+        if actual != header.payload_len {
+            return Err(FrameError::LengthMismatch {
+                expected: header.payload_len,
+                actual,
+            });
+        }
+
+        // Reuse the allocation of the incoming message for the payload
+        data.drain(..offset);
+        Ok(Frame {
+            header,
+            payload: data,
+            encodings: Encodings::default(),
+        })
+    }
+
+    /// Decompress the payload of a frame that was sent compressed, refusing payloads that grow
+    /// beyond `max_len` bytes. The compressed payload is kept, to pass it through to viewers that
+    /// accept the same codec.
+    pub fn decompress(mut self, max_len: u64) -> Result<Self, FrameError> {
+        let Some(codec) = self.header.flags.compression() else {
+            return Ok(self);
+        };
+
+        let payload = codec
+            .decompress(&self.payload, max_len)
+            .map_err(|err| FrameError::Decompression(err.to_string()))?;
+        let compressed = std::mem::replace(&mut self.payload, payload);
+        self.encodings.insert(codec, compressed);
+        self.header.flags = FrameFlags::default();
+        self.header.payload_len = self.payload.len() as u64;
+        Ok(self)
+    }
+
+    /// The payload as it is sent to a viewer that accepts `codec`
+    pub fn encoded(&self, codec: Option<Codec>) -> &[u8] {
+        match codec {
+            Some(codec) => self.encodings.get(codec, &self.payload),
+            None => &self.payload,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A frame header with the given fields, followed by `hash`
+    fn header(kind: u8, flags: u16, hash: &[u8], payload_len: u64) -> Vec<u8> {
+        let mut data = MAGIC.to_vec();
+        data.push(VERSION);
+        data.push(kind);
+        data.extend_from_slice(&flags.to_le_bytes());
+        data.extend_from_slice(&(hash.len() as u16).to_le_bytes());
+        data.extend_from_slice(&payload_len.to_le_bytes());
+        data.extend_from_slice(hash);
+        data
+    }
+
+    #[test]
+    fn parses_frame() {
+        let mut data = header(0, 0, b"abc", 4);
+        data.extend_from_slice(&[1, 2, 3, 4]);
+        let (header, offset) = FrameHeader::parse(&data).unwrap();
+        assert_eq!(offset, HEADER_LEN + 3);
+        assert_eq!((header.kind, header.flags), (FrameKind::Array, FrameFlags::default()));
+        assert_eq!((header.hash.as_str(), header.payload_len), ("abc", 4));
+
+        let frame = Frame::parse(data).unwrap();
+        assert_eq!(frame.payload, [1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn rejects_invalid_frame_headers() {
+        let parse = |data: &[u8]| FrameHeader::parse(data).unwrap_err();
+        let valid = header(0, 0, b"abc", 0);
+
+        assert_eq!(parse(&valid[..HEADER_LEN - 1]), FrameError::TooShort(HEADER_LEN - 1));
+        assert_eq!(parse(&valid[..HEADER_LEN + 1]), FrameError::TooShort(HEADER_LEN + 1));
+
+        let mut bad_magic = valid.clone();
+        bad_magic[..4].copy_from_slice(b"TVIZ");
+        assert_eq!(parse(&bad_magic), FrameError::BadMagic(*b"TVIZ"));
+
+        let mut bad_version = valid.clone();
+        bad_version[4] = VERSION + 1;
+        assert_eq!(parse(&bad_version), FrameError::UnsupportedVersion(VERSION + 1));
+
+        assert_eq!(parse(&header(0xff, 0, b"abc", 0)), FrameError::UnknownKind(0xff));
+        assert_eq!(parse(&header(0, 0x0100, b"abc", 0)), FrameError::UnknownFlags(0x0100));
+        assert_eq!(parse(&header(0, 0, b"", 0)), FrameError::EmptyHash);
+        assert_eq!(parse(&header(0, 0, b"\xff\xfe", 0)), FrameError::InvalidHash);
+    }
+
+    #[test]
+    fn rejects_payload_length_mismatch() {
+        let mut data = header(0, 0, b"abc", 4);
+        data.extend_from_slice(&[1, 2, 3]);
+        let err = Frame::parse(data).unwrap_err();
+        assert_eq!(err, FrameError::LengthMismatch { expected: 4, actual: 3 });
+    }
+    /// A chunk header for `upload_id`, without the 8-byte offset
+    fn chunk(upload_id: &[u8]) -> Vec<u8> {
+        let mut data = (upload_id.len() as u16).to_le_bytes().to_vec();
+        data.extend_from_slice(upload_id);
+        data
+    }
+
+    #[test]
+    fn parses_chunk_header() {
+        let mut data = chunk(b"upload");
+        data.extend_from_slice(&1024u64.to_le_bytes());
+        data.extend_from_slice(&[1, 2, 3]);
+        let (chunk, offset) = ChunkHeader::parse(&data).unwrap();
+        assert_eq!((chunk.upload_id.as_str(), chunk.offset), ("upload", 1024));
+        assert_eq!(&data[offset..], &[1, 2, 3]);
+    }
+
+    #[test]
+    fn rejects_invalid_chunk_headers() {
+        let parse = |data: &[u8]| ChunkHeader::parse(data).unwrap_err();
+
+        assert_eq!(parse(&[6]), FrameError::TooShort(1));
+        assert_eq!(parse(&[0, 0, 0, 0, 0, 0, 0, 0, 0, 0]), FrameError::EmptyUploadId);
+
+        // The offset is missing a byte
+        let mut truncated = chunk(b"upload");
+        truncated.extend_from_slice(&[0; 7]);
+        assert_eq!(parse(&truncated), FrameError::TooShort(truncated.len()));
+
+        let mut invalid = chunk(b"\xff\xfe");
+        invalid.extend_from_slice(&[0; 8]);
+        assert_eq!(parse(&invalid), FrameError::InvalidUploadId);
+    }
+
+    #[test]
+    fn parses_compression_flags() {
+        let flags = |bits: u16| FrameHeader::parse(&header(0, bits, b"abc", 0)).map(|(header, _)| header.flags);
+
+        assert_eq!(flags(FrameFlags::ZSTD).unwrap().compression(), Some(Codec::Zstd));
+        assert_eq!(flags(FrameFlags::LZ4).unwrap().compression(), Some(Codec::Lz4));
+        assert_eq!(flags(0x0003), Err(FrameError::ConflictingFlags(0x0003)));
+        assert_eq!(flags(0x0104), Err(FrameError::UnknownFlags(0x0104)));
+    }
+
+    #[test]
+    fn decompresses_payloads_and_keeps_them_compressed() {
+        let payload = vec![7; 1000];
+        let compressed = Codec::Zstd.compress(&payload);
+        let mut data = header(0, FrameFlags::ZSTD, b"abc", compressed.len() as u64);
+        data.extend_from_slice(&compressed);
+
+        let frame = Frame::parse(data.clone()).unwrap().decompress(1000).unwrap();
+        assert_eq!((frame.header.flags, frame.header.payload_len), (FrameFlags::default(), 1000));
+        assert_eq!(frame.payload, payload);
+        assert_eq!(frame.encoded(Some(Codec::Zstd)), compressed);
+
+        let err = Frame::parse(data).unwrap().decompress(999).unwrap_err();
+        assert!(matches!(err, FrameError::Decompression(_)));
+    }
+}
@@ -0,0 +1,426 @@
+//! Image stacks
+//!
+//! Multi-page TIFF files, and directories of PNG or TIFF slices, are read into a 5D array of
+//! shape `(t, z, y, x, c)`, the same layout as the arrays sent by the Python runtime. Every page
+//! or file is a slice, sorted by file name, and the samples of RGB(A) images form the channels.
+//! Pages written by ImageJ are split into channels, slices and frames as described by the
+//! hyperstack metadata in their image description, which also gives the resolution the unit
+//! of the slice spacing.
+//!
+//! Grayscale images keep their bit depth, e.g. a 16-bit TIFF becomes a `uint16` array.
+
+use std::cmp::Ordering;
+use std::fmt;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+
+use tiff::decoder::{Decoder, DecodingResult, Limits};
+use tiff::tags::Tag;
+use tiff::ColorType;
+
+use crate::dtype::DType;
+use crate::volume::{interleave, Volume};
+
+/// Magic bytes that start every little- and big-endian TIFF file
+pub const TIFF_MAGIC: [&[u8]; 2] = [b"II*\0", b"MM\0*"];
+
+/// Reasons an image stack can be rejected
+#[derive(Debug)]
+pub enum ImageError {
+    Io(std::io::Error),
+    Tiff(tiff::TiffError),
+    Png(png::DecodingError),
+    UnsupportedColor(String),
+    Inconsistent(String),
+    NoImages(PathBuf),
+    TooLarge(u64),
+}
+
+impl fmt::Display for ImageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImageError::Io(err) => write!(f, "could not read image: {err}"),
+            ImageError::Tiff(err) => write!(f, "invalid TIFF file: {err}"),
+            ImageError::Png(err) => write!(f, "invalid PNG file: {err}"),
+            ImageError::UnsupportedColor(color) => write!(f, "unsupported color type {color}"),
+            ImageError::Inconsistent(name) => {
+                write!(f, "`{name}` differs in size or pixel format from the previous slices")
+            }
+            ImageError::NoImages(path) => write!(f, "no PNG or TIFF images found in `{}`", path.display()),
+            ImageError::TooLarge(max_len) => write!(f, "images exceed the limit of {max_len} bytes"),
+        }
+    }
+}
+
+impl std::error::Error for ImageError {}
+
+impl From<std::io::Error> for ImageError {
+    fn from(err: std::io::Error) -> Self {
+        ImageError::Io(err)
+    }
+}
+
+impl From<tiff::TiffError> for ImageError {
+    fn from(err: tiff::TiffError) -> Self {
+        ImageError::Tiff(err)
+    }
+}
+
+impl From<png::DecodingError> for ImageError {
+    fn from(err: png::DecodingError) -> Self {
+        ImageError::Png(err)
+    }
+}
+
+/// A single decoded page or file, with its samples interleaved and in little-endian order
+#[derive(Debug)]
+struct Plane {
+    width: usize,
+    height: usize,
+    channels: usize,
+    dtype: DType,
+    data: Vec<u8>,
+}
+
+/// Dimensions and spacing of an ImageJ hyperstack, read from the image description
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Hyperstack {
+    channels: usize,
+    slices: usize,
+    frames: usize,
+    spacing: Option<f64>,
+    interval: Option<f64>,
+}
+
+impl Hyperstack {
+    fn parse(description: &str) -> Option<Self> {
+        if !description.starts_with("ImageJ=") {
+            return None;
+        }
+        let value = |key: &str| {
+            description
+                .lines()
+                .find_map(|line| line.strip_prefix(key)?.strip_prefix('='))
+                .and_then(|value| value.trim().parse::<f64>().ok())
+        };
+        let count = |key: &str| value(key).map_or(1, |v| v.max(1.0) as usize);
+        Some(Hyperstack {
+            channels: count("channels"),
+            slices: count("slices"),
+            frames: count("frames"),
+            spacing: value("spacing"),
+            interval: value("finterval"),
+        })
+    }
+}
+
+/// Number of samples per pixel of a TIFF color type, if supported
+fn tiff_channels(color: ColorType) -> Result<usize, ImageError> {
+    match color {
+        ColorType::Gray(_) => Ok(1),
+        ColorType::GrayA(_) => Ok(2),
+        ColorType::RGB(_) => Ok(3),
+        ColorType::RGBA(_) | ColorType::CMYK(_) => Ok(4),
+        other => Err(ImageError::UnsupportedColor(format!("{other:?}"))),
+    }
+}
+
+/// Raw little-endian bytes of a decoded TIFF page, and their data type
+fn tiff_samples(result: DecodingResult) -> (DType, Vec<u8>) {
+    fn bytes<T, const N: usize>(values: Vec<T>, to_le: fn(T) -> [u8; N]) -> Vec<u8> {
+        values.into_iter().flat_map(to_le).collect()
+    }
+    match result {
+        DecodingResult::U8(v) => (DType::Uint8, v),
+        DecodingResult::I8(v) => (DType::Int8, bytes(v, i8::to_le_bytes)),
+        DecodingResult::U16(v) => (DType::Uint16, bytes(v, u16::to_le_bytes)),
+        DecodingResult::I16(v) => (DType::Int16, bytes(v, i16::to_le_bytes)),
+        DecodingResult::U32(v) => (DType::Uint32, bytes(v, u32::to_le_bytes)),
+        DecodingResult::I32(v) => (DType::Int32, bytes(v, i32::to_le_bytes)),
+        DecodingResult::U64(v) => (DType::Uint64, bytes(v, u64::to_le_bytes)),
+        DecodingResult::I64(v) => (DType::Int64, bytes(v, i64::to_le_bytes)),
+        DecodingResult::F32(v) => (DType::Float32, bytes(v, f32::to_le_bytes)),
+        DecodingResult::F64(v) => (DType::Float64, bytes(v, f64::to_le_bytes)),
+    }
+}
+
+/// Pixel size in the x and y directions, from the resolution of a TIFF page. Only ImageJ writes
+/// the resolution in the unit of the slice spacing, in other files it is usually a print
+/// resolution in dots per inch.
+fn tiff_pixel_size<R: std::io::Read + std::io::Seek>(decoder: &mut Decoder<R>) -> Option<[f64; 2]> {
+    let mut resolution = |tag| match decoder.find_tag(tag).ok()?? {
+        tiff::decoder::ifd::Value::Rational(n, d) if n > 0 && d > 0 => Some(d as f64 / n as f64),
+        _ => None,
+    };
+    Some([resolution(Tag::XResolution)?, resolution(Tag::YResolution)?])
+}
+
+/// The decoded pages of a TIFF file, and the metadata of the first page
+struct TiffFile {
+    pages: Vec<Plane>,
+    hyperstack: Option<Hyperstack>,
+    pixel_size: Option<[f64; 2]>,
+}
+
+/// Decode every page of a TIFF file, refusing pages that take up more than `max_len` bytes
+/// together
+fn read_pages(data: &[u8], max_len: u64) -> Result<TiffFile, ImageError> {
+    // Compressed pages can declare far larger dimensions than the file itself
+    let mut limits = Limits::default();
+    limits.decoding_buffer_size = usize::try_from(max_len).unwrap_or(usize::MAX);
+    limits.intermediate_buffer_size = limits.decoding_buffer_size;
+    let mut decoder = Decoder::new(Cursor::new(data))?.with_limits(limits);
+    let description = decoder.get_tag_ascii_string(Tag::ImageDescription).ok();
+    let hyperstack = description.as_deref().and_then(Hyperstack::parse);
+    let pixel_size = hyperstack.and_then(|_| tiff_pixel_size(&mut decoder));
+
+    let mut pages = Vec::new();
+    let mut len = 0;
+    loop {
+        let (width, height) = decoder.dimensions()?;
+        let channels = tiff_channels(decoder.colortype()?)?;
+        let (dtype, data) = match decoder.read_image() {
+            Err(tiff::TiffError::LimitsExceeded) => return Err(ImageError::TooLarge(max_len)),
+            result => tiff_samples(result?),
+        };
+        len += data.len() as u64;
+        if len > max_len {
+            return Err(ImageError::TooLarge(max_len));
+        }
+        pages.push(Plane {
+            width: width as usize,
+            height: height as usize,
+            channels,
+            dtype,
+            data,
+        });
+
+        if !decoder.more_images() {
+            break;
+        }
+        decoder.next_image()?;
+    }
+    Ok(TiffFile {
+        pages,
+        hyperstack,
+        pixel_size,
+    })
+}
+
+/// Decode a PNG file, expanding palettes and bit depths below 8 bits, refusing images that take
+/// up more than `max_len` bytes
+fn png_plane(data: &[u8], max_len: u64) -> Result<Plane, ImageError> {
+    let bytes = usize::try_from(max_len).unwrap_or(usize::MAX);
+    let mut decoder = png::Decoder::new_with_limits(data, png::Limits { bytes });
+    decoder.set_transformations(png::Transformations::EXPAND);
+    let mut reader = decoder.read_info()?;
+    if reader.output_buffer_size() > bytes {
+        return Err(ImageError::TooLarge(max_len));
+    }
+    let mut buf = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut buf)?;
+    buf.truncate(info.buffer_size());
+
+    // 16-bit samples are stored big-endian
+    let dtype = match info.bit_depth {
+        png::BitDepth::Sixteen => {
+            buf.chunks_exact_mut(2).for_each(|item| item.reverse());
+            DType::Uint16
+        }
+        _ => DType::Uint8,
+    };
+    Ok(Plane {
+        width: info.width as usize,
+        height: info.height as usize,
+        channels: info.color_type.samples(),
+        dtype,
+        data: buf,
+    })
+}
+
+/// Stack planes into a `(t, z, y, x, c)` volume. The planes of a hyperstack hold a single
+/// channel each, ordered by channel, then slice, then frame.
+fn stack(planes: Vec<Plane>, names: &[String], hyperstack: Option<Hyperstack>) -> Result<Volume, ImageError> {
+    let first = &planes[0];
+    let (width, height, channels, dtype) = (first.width, first.height, first.channels, first.dtype);
+    for (plane, name) in planes.iter().zip(names) {
+        if (plane.width, plane.height, plane.channels, plane.dtype) != (width, height, channels, dtype) {
+            return Err(ImageError::Inconsistent(name.clone()));
+        }
+    }
+
+    let hyperstack = hyperstack.filter(|h| {
+        h.channels * h.slices * h.frames == planes.len() && (channels == 1 || h.channels == 1)
+    });
+    let (frames, slices, channels) = match hyperstack {
+        Some(h) => (h.frames, h.slices, h.channels * channels),
+        None => (1, planes.len(), channels),
+    };
+
+    let mut data: Vec<u8> = planes.into_iter().flat_map(|plane| plane.data).collect();
+    if hyperstack.is_some_and(|h| h.channels > 1) {
+        let plane_len = width * height * dtype.itemsize();
+        data = data
+            .chunks_exact(plane_len * channels)
+            .flat_map(|planes| interleave(planes, channels, dtype.itemsize()))
+            .collect();
+    }
+
+    let mut volume = Volume::new(vec![frames, slices, height, width, channels], dtype, data);
+    if let Some(h) = hyperstack {
+        volume.spacing = Some(vec![h.interval.unwrap_or(1.0), h.spacing.unwrap_or(1.0), 1.0, 1.0, 1.0]);
+    }
+    Ok(volume)
+}
+
+/// Read a multi-page TIFF file of at most `max_len` bytes once decoded
+pub fn read_tiff(data: &[u8], max_len: u64) -> Result<Volume, ImageError> {
+    let tiff = read_pages(data, max_len)?;
+    let names: Vec<String> = (0..tiff.pages.len()).map(|i| format!("page {i}")).collect();
+
+    let mut volume = stack(tiff.pages, &names, tiff.hyperstack)?;
+    if let Some([dx, dy]) = tiff.pixel_size {
+        let spacing = volume.spacing.get_or_insert_with(|| vec![1.0; 5]);
+        (spacing[2], spacing[3]) = (dy, dx);
+    }
+    Ok(volume)
+}
+
+/// Whether a path has the extension of an image that can be stacked
+pub fn is_image(path: &Path) -> bool {
+    let extension = path.extension().and_then(|e| e.to_str()).map(str::to_lowercase);
+    matches!(extension.as_deref(), Some("png" | "tif" | "tiff"))
+}
+
+/// Compare file names such that `slice_2.png` comes before `slice_10.png`
+fn natural_cmp(a: &str, b: &str) -> Ordering {
+    let (mut a, mut b) = (a, b);
+    loop {
+        let (Some(x), Some(y)) = (a.chars().next(), b.chars().next()) else {
+            return a.len().cmp(&b.len());
+        };
+        let ordering = if x.is_ascii_digit() && y.is_ascii_digit() {
+            let digits = |s: &str| s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+            let (i, j) = (digits(a), digits(b));
+            let (n, m) = (a[..i].trim_start_matches('0'), b[..j].trim_start_matches('0'));
+            let ordering = n.len().cmp(&m.len()).then_with(|| n.cmp(m));
+            (a, b) = (&a[i..], &b[j..]);
+            ordering
+        } else {
+            (a, b) = (&a[x.len_utf8()..], &b[y.len_utf8()..]);
+            x.cmp(&y)
+        };
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+}
+
+/// Read the PNG and TIFF images in a directory, sorted by name, as the slices of a volume of at
+/// most `max_len` bytes
+pub fn read_stack(dir: &Path, max_len: u64) -> Result<Volume, ImageError> {
+    let mut paths = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_file() && is_image(&path) {
+            paths.push(path);
+        }
+    }
+    if paths.is_empty() {
+        return Err(ImageError::NoImages(dir.to_owned()));
+    }
+    paths.sort_by(|a, b| natural_cmp(&a.to_string_lossy(), &b.to_string_lossy()));
+
+    let mut planes = Vec::new();
+    let mut names = Vec::new();
+    let mut remaining = max_len;
+    for path in paths {
+        let data = std::fs::read(&path)?;
+        let name = path.display().to_string();
+        let pages = if TIFF_MAGIC.iter().any(|magic| data.starts_with(magic)) {
+            read_pages(&data, remaining)?.pages
+        } else {
+            vec![png_plane(&data, remaining)?]
+        };
+        remaining -= pages.iter().map(|page| page.data.len() as u64).sum::<u64>();
+        names.extend(std::iter::repeat_n(name, pages.len()));
+        planes.extend(pages);
+    }
+
+    println!("--- reading {} slices from `{}`", planes.len(), dir.display());
+    stack(planes, &names, None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tiff::encoder::{colortype, Rational, TiffEncoder};
+
+    const MAX_LEN: u64 = 1 << 30;
+
+    /// A TIFF file of 8-bit grayscale pages of `width` by `height` pixels, the first of which has
+    /// the image description and resolution given
+    fn tiff(
+        width: u32,
+        height: u32,
+        pages: &[&[u8]],
+        description: Option<&str>,
+        resolution: Option<[u32; 2]>,
+    ) -> Vec<u8> {
+        let mut data = Cursor::new(Vec::new());
+        let mut encoder = TiffEncoder::new(&mut data).unwrap();
+        for (i, page) in pages.iter().enumerate() {
+            let mut image = encoder.new_image::<colortype::Gray8>(width, height).unwrap();
+            if i == 0 {
+                if let Some(description) = description {
+                    image.encoder().write_tag(Tag::ImageDescription, description).unwrap();
+                }
+                if let Some([x, y]) = resolution {
+                    image.x_resolution(Rational { n: x, d: 1 });
+                    image.y_resolution(Rational { n: y, d: 1 });
+                }
+            }
+            image.write_data(page).unwrap();
+        }
+        data.into_inner()
+    }
+
+    #[test]
+    fn stacks_pages_as_slices() {
+        let data = tiff(3, 2, &[&[1, 2, 3, 4, 5, 6], &[7, 8, 9, 10, 11, 12]], None, Some([300, 300]));
+        let volume = read_tiff(&data, MAX_LEN).unwrap();
+
+        assert_eq!((volume.shape, volume.dtype), (vec![1, 2, 2, 3, 1], DType::Uint8));
+        assert_eq!(volume.data, (1..=12).collect::<Vec<u8>>());
+
+        // The resolution of files not written by ImageJ is a print resolution
+        assert_eq!(volume.spacing, None);
+    }
+
+    #[test]
+    fn splits_imagej_hyperstacks() {
+        // Two frames of a single slice with two channels, stored channel by channel
+        let description = "ImageJ=1.53t\nimages=4\nchannels=2\nframes=2\nhyperstack=true\nunit=micron\nfinterval=0.5\n";
+        let pages: [&[u8]; 4] = [&[1, 2], &[10, 20], &[3, 4], &[30, 40]];
+        let data = tiff(2, 1, &pages, Some(description), Some([4, 2]));
+        let volume = read_tiff(&data, MAX_LEN).unwrap();
+
+        assert_eq!(volume.shape, [2, 1, 1, 2, 2]);
+        assert_eq!(volume.data, [1, 10, 2, 20, 3, 30, 4, 40]);
+        assert_eq!(volume.spacing, Some(vec![0.5, 1.0, 0.5, 0.25, 1.0]));
+    }
+
+    #[test]
+    fn ignores_hyperstacks_that_do_not_match_the_pages() {
+        let description = "ImageJ=1.53t\nimages=4\nchannels=2\nslices=2\n";
+        let volume = read_tiff(&tiff(2, 1, &[&[1, 2], &[3, 4]], Some(description), None), MAX_LEN).unwrap();
+        assert_eq!(volume.shape, [1, 2, 1, 2, 1]);
+    }
+
+    #[test]
+    fn rejects_pages_beyond_limit() {
+        let data = tiff(2, 2, &[&[0; 4], &[0; 4]], None, None);
+        assert!(matches!(read_tiff(&data, 6), Err(ImageError::TooLarge(6))));
+        assert!(read_tiff(&data, 8).is_ok());
+    }
+}
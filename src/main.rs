@@ -13,31 +13,60 @@
 //! firefox http://localhost:8765/ws
 //! ```
 
+mod api;
+mod compression;
+mod convert;
+mod dicom;
+mod dtype;
+mod frame;
+mod images;
+mod message;
+mod mapped;
+mod nifti;
+mod npy;
+mod pyramid;
+mod statistics;
+mod upload;
+mod volume;
+mod websocket;
+mod zarr;
+
 use std::sync::Mutex;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
 use std::{net::SocketAddr, ops::ControlFlow, path::PathBuf, sync::Arc};
 
-use axum::{Router, routing::get};
-use axum::body::{boxed, Body};
+use axum::{Json, Router, routing::{get, post}};
+use axum::extract::DefaultBodyLimit;
+use axum::body::{boxed, Body, BoxBody};
 use axum::extract::{State, TypedHeader};
 use axum::extract::connect_info::ConnectInfo;
-use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
-use axum::http::{Request, Response, StatusCode, Method};
+use axum::http::{header, HeaderName, Request, Response, StatusCode, Method};
+use axum::middleware::{self, Next};
 use axum::response::IntoResponse;
 use clap::Parser;
 use futures::{sink::SinkExt, stream::StreamExt};
 use mime_guess::from_path;
-use serde::{Deserialize, Serialize};
 use tokio::fs;
-use tokio::sync::broadcast;
+use tokio::sync::{mpsc, mpsc::error::TrySendError};
+use tokio_tungstenite::tungstenite::Message;
 use tower::{ServiceExt};
 use tower_http::{
-    cors::{Any, CorsLayer},
+    cors::{AllowOrigin, Any, CorsLayer},
     services::ServeDir,
     trace::{DefaultMakeSpan, TraceLayer},
 };
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
+use compression::Codec;
+use frame::{ChunkHeader, Frame, FrameKind};
+use message::{ArrayHeader, ConnectionId, DeliveryFailure, Envelope, ErrorCode};
+use upload::Upload;
+use dtype::{ByteOrder, DType};
+use volume::Volume;
+use websocket::WebSocket;
+
 // Parse CLI arguments using Clap
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -47,32 +76,217 @@ struct Args {
 
     #[arg(short = 'd', long = "dist", default_value = "./dist")]
     static_dir: String,
+
+    /// Largest websocket message, in MiB; larger arrays have to be sent as a chunked upload
+    #[arg(long = "max-message-size", default_value = "256")]
+    max_message_size: usize,
+
+    /// Largest array, in MiB, that is relayed to viewers
+    #[arg(long = "max-payload-size", default_value = "4096")]
+    max_payload_size: u64,
+
+    /// Compress text messages with the permessage-deflate extension, for clients that offer it
+    #[arg(long = "permessage-deflate")]
+    permessage_deflate: bool,
+
+    /// Convert arrays to little-endian elements of a dtype viewers handle well, e.g. float64 to
+    /// float32, and rewrite their header to match
+    #[arg(long = "convert-dtypes")]
+    convert_dtypes: bool,
+
+    /// Compute the minimum, maximum, mean, percentiles and histogram of every channel of an
+    /// array, and send them in its header
+    #[arg(long = "statistics")]
+    statistics: bool,
+
+    /// Seconds to wait for a viewer to answer a request
+    #[arg(long = "request-timeout", default_value = "10")]
+    request_timeout: u64,
+
+    /// Build a pyramid of arrays larger than this many MiB, and send viewers its coarsest level,
+    /// of at most this size, first
+    #[arg(long = "pyramid", value_name = "MIB")]
+    pyramid: Option<u64>,
+
+    /// Directory, besides the temporary directory of the system, that `mapped` messages can map
+    /// files from
+    #[arg(long = "mapped-dir", value_name = "DIR")]
+    mapped_dirs: Vec<PathBuf>,
+
+    /// Directory that clients can open files from, with `open` messages or requests. Clients can
+    /// not open files unless it is given.
+    #[arg(long = "open-dir", value_name = "DIR")]
+    open_dirs: Vec<PathBuf>,
+
+    /// Origin of a web page, besides the server itself, that may open websockets to the server
+    #[arg(long = "allow-origin", value_name = "ORIGIN")]
+    allowed_origins: Vec<String>,
+
+    /// File or directory of DICOM files to open at startup, kept for the viewers of `HASH`
+    #[arg(long = "open", value_name = "HASH=PATH", value_parser = parse_open)]
+    open: Vec<(String, PathBuf)>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
-struct GUIClientHandshake {
-    connected: bool,
-    hash: String,
+/// Parse the `HASH=PATH` value of `--open`
+fn parse_open(value: &str) -> Result<(String, PathBuf), String> {
+    match value.split_once('=') {
+        Some((hash, path)) if !hash.is_empty() && !path.is_empty() => Ok((hash.to_owned(), path.into())),
+        _ => Err(format!("expected `HASH=PATH`, got `{value}`")),
+    }
+}
+
+/// Number of binary payloads that can be queued for a single client before the sender has to wait
+const OUTBOUND_CAPACITY: usize = 4;
+
+/// Number of text messages that can be queued for a single client before it has fallen behind
+const TEXT_CAPACITY: usize = 1000;
+
+/// Time the latest array of a hash is kept once its publisher and all its viewers are gone
+const LATEST_EXPIRY: Duration = Duration::from_secs(10 * 60);
+
+/// The latest header and payload sent for a hash, or the Zarr store opened for it instead
+#[derive(Debug, Clone, Default)]
+struct Latest {
+    header: Option<ArrayHeader>,
+    frame: Option<Arc<Frame>>,
+    store: Option<zarr::Store>,
+
+    /// The coarser levels of the pyramid of a large array, finest first
+    levels: Vec<(ArrayHeader, Arc<Frame>)>,
+
+    /// When the publisher and the last viewer of the hash went away, if they did
+    orphaned: Option<Instant>,
+}
+
+impl Latest {
+    /// The header and payload viewers are sent first: the coarsest level of the pyramid, if one
+    /// was built, or the array itself
+    fn first(&self) -> (Option<ArrayHeader>, Option<Arc<Frame>>) {
+        match self.levels.last() {
+            Some((header, frame)) => (Some(header.clone()), Some(frame.clone())),
+            None => (self.header.clone(), self.frame.clone()),
+        }
+    }
+
+    /// The header and payload of a level of the pyramid, where level `0` is the array itself
+    fn level(&self, level: usize) -> Option<(ArrayHeader, Arc<Frame>)> {
+        match level {
+            0 => Some((self.header.clone()?, self.frame.clone()?)),
+            n => self.levels.get(n - 1).cloned(),
+        }
+    }
+}
+
+/// A request that was forwarded to a viewer and is waiting for its response
+#[derive(Debug, Clone, Copy)]
+struct PendingRequest {
+    from: ConnectionId,
+    to: ConnectionId,
+}
+
+/// The queues of messages addressed to a single client
+#[derive(Debug, Clone)]
+struct Outbound {
+    text: mpsc::Sender<String>,
+    frames: mpsc::Sender<Arc<Frame>>,
+
+    // Number of text messages dropped since the client last resynchronized
+    skipped: Arc<AtomicU64>,
 }
 
 struct AppState {
-    // Store the address of all connected clients, with a hash that uniquely identifies the client
-    clients: Arc<Mutex<HashMap<String, SocketAddr>>>,
+    // Store the ids of all connected clients, grouped by the hash they are viewing
+    clients: Arc<Mutex<HashMap<String, HashSet<ConnectionId>>>>,
+
+    // Store the id of the client that last sent a header for a hash, i.e. the Python runtime
+    publishers: Arc<Mutex<HashMap<String, ConnectionId>>>,
+
+    // Queues of outgoing text messages and binary payloads for every connected client
+    outbound: Arc<Mutex<HashMap<ConnectionId, Outbound>>>,
+
+    // The codec every client that negotiated compression is sent its payloads with
+    codecs: Arc<Mutex<HashMap<ConnectionId, Codec>>>,
+
+    // The latest header and payload sent for every hash, replayed to clients that register late
+    // or need to resynchronize
+    latest: Arc<Mutex<HashMap<String, Latest>>>,
+
+    // Number of times a client fell too far behind on its text queue
+    lag_events: AtomicU64,
+
+    // The id that will be assigned to the next connection
+    next_id: AtomicU64,
+
+    // Requests waiting for a response from a viewer, by their correlation id
+    requests: Arc<Mutex<HashMap<String, PendingRequest>>>,
 
-    // Broadcast channel for sending messages to all clients
-    tx: broadcast::Sender<Message>,
+    // Chunked uploads that are being reassembled, by their upload id
+    uploads: Arc<Mutex<HashMap<String, Upload>>>,
+
+    // Largest websocket message, in bytes
+    max_message_size: usize,
+
+    // Whether text messages are compressed for clients that offer permessage-deflate
+    permessage_deflate: bool,
+
+    // Largest array, in bytes, that is relayed to viewers
+    max_payload_size: u64,
+
+    // Time a viewer gets to answer a request
+    request_timeout: Duration,
+
+    // Whether arrays are converted to a dtype viewers handle well before they are relayed
+    convert_dtypes: bool,
+
+    // Whether the statistics of arrays are computed and sent in their header
+    statistics: bool,
+
+    // Arrays larger than this many bytes are sent as a pyramid, coarsest level first
+    pyramid_size: Option<u64>,
+
+    // Directories that `mapped` messages can map files from
+    mapped_dirs: Vec<PathBuf>,
+
+    // Directories that clients can open files from
+    open_dirs: Vec<PathBuf>,
+
+    // Origins of web pages, besides the server itself, that may open websockets
+    allowed_origins: Vec<String>,
 }
 
 impl Default for AppState {
     fn default() -> Self {
-        let (tx, _) = broadcast::channel(1000);
         Self {
             clients: Arc::new(Mutex::new(HashMap::new())),
-            tx,
+            publishers: Arc::new(Mutex::new(HashMap::new())),
+            outbound: Arc::new(Mutex::new(HashMap::new())),
+            codecs: Arc::new(Mutex::new(HashMap::new())),
+            latest: Arc::new(Mutex::new(HashMap::new())),
+            lag_events: AtomicU64::new(0),
+            next_id: AtomicU64::new(1),
+            requests: Arc::new(Mutex::new(HashMap::new())),
+            uploads: Arc::new(Mutex::new(HashMap::new())),
+            max_message_size: 256 * 1024 * 1024,
+            permessage_deflate: false,
+            max_payload_size: 4096 * 1024 * 1024,
+            request_timeout: Duration::from_secs(10),
+            convert_dtypes: false,
+            statistics: false,
+            pyramid_size: None,
+            mapped_dirs: vec![std::env::temp_dir()],
+            open_dirs: Vec::new(),
+            allowed_origins: Vec::new(),
         }
     }
 }
 
+impl AppState {
+    /// Hand out a new, unique connection id
+    fn connection_id(&self) -> ConnectionId {
+        ConnectionId(self.next_id.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
 #[tokio::main]
 async fn main() {
     // Parse CLI arguments
@@ -88,13 +302,54 @@ async fn main() {
         .init();
 
     // Create the app state
-    let app_state = Arc::new(AppState::default());
+    let app_state = Arc::new(AppState {
+        max_message_size: args.max_message_size * 1024 * 1024,
+        permessage_deflate: args.permessage_deflate,
+        max_payload_size: args.max_payload_size * 1024 * 1024,
+        request_timeout: Duration::from_secs(args.request_timeout),
+        convert_dtypes: args.convert_dtypes,
+        statistics: args.statistics,
+        pyramid_size: args.pyramid.map(|size| size * 1024 * 1024),
+        mapped_dirs: std::iter::once(std::env::temp_dir()).chain(args.mapped_dirs).collect(),
+        open_dirs: args.open_dirs,
+        allowed_origins: args.allowed_origins,
+        ..Default::default()
+    });
+
+    // Files opened from the command line are kept until a viewer registers for their hash
+    for (hash, path) in args.open.clone() {
+        let state = app_state.clone();
+        tokio::spawn(async move {
+            let who = state.connection_id();
+            open_file(&state, who, hash, path, None).await;
+        });
+    }
+
+    // Web pages of other origins may fetch arrays, but only allowed ones may post them or open files
+    let allowed_origins = app_state.allowed_origins.clone();
+    let cors_origin = AllowOrigin::predicate(move |_, parts| {
+        let method = match parts.method {
+            Method::OPTIONS => parts.headers.get(header::ACCESS_CONTROL_REQUEST_METHOD).and_then(|v| v.to_str().ok()),
+            ref method => Some(method.as_str()),
+        };
+        method == Some("GET") || websocket::trusted_origin(&parts.headers, &allowed_origins)
+    });
 
     // build our application with some routes
     let app = Router::new()
         // Setup a WebSocket route
         .route("/api/hello", get(hello))
+        .route("/api/metrics", get(metrics))
+        .route(
+            "/api/arrays/:hash",
+            get(api::download_array)
+                .post(api::upload_array)
+                .layer(DefaultBodyLimit::max(args.max_payload_size as usize * 1024 * 1024)),
+        )
+        .route("/api/open/:hash", post(api::open_file))
+        .route("/api/zarr/:hash/*key", get(api::zarr_key))
         .route("/ws", get(ws_handler))
+        .layer(middleware::from_fn_with_state(app_state.clone(), check_origin))
         .with_state(app_state)
 
         // Serve static files, such as the SPA
@@ -138,8 +393,14 @@ async fn main() {
         // CORS
         .layer(
             CorsLayer::new()
-                .allow_origin(Any)
-                .allow_methods([Method::GET])
+                .allow_origin(cors_origin)
+                .allow_methods([Method::GET, Method::POST])
+                .allow_headers(Any)
+                .expose_headers([
+                    header::CONTENT_RANGE,
+                    HeaderName::from_static(api::SHAPE_HEADER),
+                    HeaderName::from_static(api::DTYPE_HEADER),
+                ])
         )
 
         // Logging so we can see whats going on
@@ -163,16 +424,29 @@ async fn main() {
 
 }
 
+/// Refuse POST requests from web pages of other origins. Browsers send simple cross-origin
+/// requests without asking first, so CORS alone does not keep them from opening files or
+/// replacing the arrays a viewer shows.
+async fn check_origin<B>(State(state): State<Arc<AppState>>, request: Request<B>, next: Next<B>) -> Response<BoxBody> {
+    if request.method() == Method::POST && !websocket::trusted_origin(request.headers(), &state.allowed_origins) {
+        return Response::builder()
+            .status(StatusCode::FORBIDDEN)
+            .body(boxed(Body::from("`Origin` is not allowed to post to the server")))
+            .unwrap();
+    }
+    next.run(request).await.map(boxed)
+}
+
 /// The handler for the HTTP request (this gets called when the HTTP GET lands at the start
 /// of websocket negotiation). After this completes, the actual switching from HTTP to
 /// websocket protocol will occur.
 /// This is the last point where we can extract TCP/IP metadata such as IP address of the client
 /// as well as things from HTTP headers such as user-agent of the browser etc.
 async fn ws_handler(
-    ws: WebSocketUpgrade,
     user_agent: Option<TypedHeader<headers::UserAgent>>,
     ConnectInfo(addr): ConnectInfo<SocketAddr>,
     State(state): State<Arc<AppState>>,
+    request: Request<Body>,
 ) -> impl IntoResponse {
     let user_agent = if let Some(TypedHeader(user_agent)) = user_agent {
         user_agent.to_string()
@@ -184,14 +458,21 @@ async fn ws_handler(
 
     // finalize the upgrade process by returning upgrade callback.
     // we can customize the callback by sending additional info such as address.
-    let max_size = 256 * 1024 * 1024;
-    ws.max_frame_size(max_size)
-        .max_message_size(max_size)
-        .on_upgrade(move |socket| handle_socket(socket, addr, state))
+    let config = websocket::Config {
+        max_message_size: state.max_message_size,
+        permessage_deflate: state.permessage_deflate,
+        allowed_origins: state.allowed_origins.clone(),
+    };
+    websocket::upgrade(request, config, move |socket| handle_socket(socket, addr, state)).await
 }
 
 /// Actual websocket statemachine (one will be spawned per connection)
-async fn handle_socket(socket: WebSocket, who: SocketAddr, state: Arc<AppState>) {
+async fn handle_socket(socket: WebSocket, addr: SocketAddr, state: Arc<AppState>) {
+    // Identify the connection by an id of its own, since many connections can share an address
+    // behind a proxy or port forwarding
+    let who = state.connection_id();
+    println!("--- {} is connection {}", addr, who);
+
     // By splitting socket we can send and receive at the same time. In this example we will send
     // unsolicited messages to client based on some sort of server's internal event (i.e .timer).
     let (mut sender, mut receiver) = socket.split();
@@ -206,72 +487,111 @@ async fn handle_socket(socket: WebSocket, who: SocketAddr, state: Arc<AppState>)
         return;
     }
 
-    // Subscribe to the broadcast channel
-    let mut rx = state.clone().tx.subscribe();
+    // Let the client know its id
+    if sender.send(Message::Text(Envelope::Welcome { connection_id: who }.to_text())).await.is_err() {
+        println!("Could not welcome {}!", who);
+        return;
+    }
+
+    // Channel for messages addressed only to this client, such as errors
+    let (reply_tx, mut reply_rx) = mpsc::unbounded_channel::<Envelope>();
+
+    // Queues for the text messages and binary payloads other clients address to this client
+    let (text_tx, mut text_rx) = mpsc::channel::<String>(TEXT_CAPACITY);
+    let (frame_tx, mut frame_rx) = mpsc::channel::<Arc<Frame>>(OUTBOUND_CAPACITY);
+    let skipped = Arc::new(AtomicU64::new(0));
+    let outbound = Outbound { text: text_tx, frames: frame_tx, skipped: skipped.clone() };
+    state.outbound.lock().unwrap().insert(who, outbound);
 
-    // Spawn the first task that will receive broadcast messages and send text
-    // messages over the websocket to our client.
+    // Spawn the first task that will receive relayed messages, replies and binary payloads,
+    // and send them over the websocket to our client.
     let s = state.clone();
+    let resync_tx = reply_tx.clone();
     let mut send_task = tokio::spawn(async move {
-        while let Ok(msg) = rx.recv().await {
-            // In any websocket error, break loop.
-            match msg {
-                // Forward text messages to all clients, including the origin
-                Message::Text(_) => {
-                    if sender.send(msg).await.is_err() {
-                        break;
+        loop {
+            // Messages were dropped, let the client know and send it the latest state again
+            let skipped = skipped.swap(0, Ordering::Relaxed);
+            if skipped > 0 {
+                s.lag_events.fetch_add(1, Ordering::Relaxed);
+                println!("--- {} lagged behind by {} messages", who, skipped);
+                let _ = resync_tx.send(Envelope::Resync { skipped });
+
+                // Queued payloads may belong to headers that were dropped, the replay sends the
+                // latest header and payload of every hash in order
+                while frame_rx.try_recv().is_ok() {}
+                let s = s.clone();
+                let resync_tx = resync_tx.clone();
+                tokio::spawn(async move {
+                    for hash in registered(&s, who) {
+                        replay(&s, who, &hash, &resync_tx).await;
                     }
-                },
+                });
+            }
 
-                // Forward binary messages to a receiver with a matching hash
-                Message::Binary(d) => {
-                    println!("--- trying to forward binary to {}", who);
-                    if d.len() > 22 {
-                        let (hash, data) = d.split_at(22);
-                        if let Ok(hash) = String::from_utf8(hash.to_vec()) {
-                            let clients = s.clients.lock().unwrap().clone();
-                            if let Some(client) = clients.get(&hash) {
-                                if client == &who {
-                                    if sender.send(Message::Binary(Vec::from(data))).await.is_err() {
-                                        println!("--- {} unexpectedly rejected binary message", who);
-                                        break;
-                                    }
-                                    println!("--- {} accepted binary message", who);
-                                }
-                            } else {
-                                println!("--- {} rejected binary message", who);
-                            }
+            // Text is polled first, so that a header always precedes the payload it describes
+            let msg = tokio::select! {
+                biased;
+
+                Some(text) = text_rx.recv() => Message::Text(text),
+                Some(reply) = reply_rx.recv() => Message::Text(reply.to_text()),
+                Some(frame) = frame_rx.recv() => {
+                    let codec = s.codecs.lock().unwrap().get(&who).copied();
+                    match codec {
+                        None => {
+                            println!("--- forwarding {} bytes to {}", frame.payload.len(), who);
+
+                            // Avoid copying the payload if no one else holds on to it
+                            let payload = Arc::try_unwrap(frame)
+                                .map(|frame| frame.payload)
+                                .unwrap_or_else(|frame| frame.payload.clone());
+                            Message::Binary(payload)
+                        }
+
+                        // The payload is compressed once, for all viewers that accept the codec
+                        Some(codec) => {
+                            let len = frame.payload.len();
+                            let payload = tokio::task::spawn_blocking(move || frame.encoded(Some(codec)).to_vec())
+                                .await
+                                .unwrap();
+                            println!("--- forwarding {} bytes as {} bytes of {} to {}", len, payload.len(), codec.name(), who);
+                            Message::Binary(payload)
                         }
                     }
                 },
-                _ => {}
+                else => break,
+            };
+
+            // In any websocket error, break loop.
+            if sender.send(msg).await.is_err() {
+                println!("--- {} unexpectedly rejected message", who);
+                break;
             }
         }
     });
 
 
     let s = state.clone();
-    // Spawn a task that receives messages from the websocket and forwards them to the broadcast channel
+    // Spawn a task that receives messages from the websocket and forwards them to the other clients
     let mut recv_task = tokio::spawn(async move {
         while let Some(Ok(msg)) = receiver.next().await {
             match msg {
-                // Messages are forwarded to all clients, including the sender; also handles new connections
+                // Messages are parsed into an envelope, invalid messages are answered with an error
                 Message::Text(t) => {
                     println!(">>> {} sent str: {:?}", who, t);
-                    if let Ok(client) = serde_json::from_str::<GUIClientHandshake>(&t) {
-                        // Add client to the list of clients
-                        println!("--- {} added to client list", who);
-                        s.clone().clients.lock().unwrap().insert(client.hash, who);
+                    match Envelope::parse(&t) {
+                        Ok(envelope) => handle_envelope(&s, who, &reply_tx, envelope).await,
+                        Err(err) => {
+                            println!("--- {} sent invalid message: {}", who, err);
+                            let _ = reply_tx.send(Envelope::error(ErrorCode::InvalidMessage, err));
+                        }
                     }
-
-                    // // Forward the message to _all_ clients
-                    s.tx.send(Message::Text(t)).expect("Could not send message to broadcast channel");
                 }
 
                 Message::Binary(d) => {
-                    // Forward the message to all clients
                     println!(">>> {} sent {} bytes", who, d.len());
-                    s.tx.send(Message::Binary(d)).expect("Could not send message to broadcast channel");
+                    if let Some(delivery) = handle_binary(&s, who, d).await {
+                        let _ = reply_tx.send(delivery);
+                    }
                 }
 
                 Message::Close(c) => {
@@ -284,26 +604,19 @@ async fn handle_socket(socket: WebSocket, who: SocketAddr, state: Arc<AppState>)
                         println!(">>> {} somehow sent close message without CloseFrame", who);
                     }
 
-                    // Remove client from the list of clients
-                    println!("--- {} removed from client list", &who);
-                    s.clone().clients.lock().unwrap().retain(|_, v| v != &who);
-
-                    println!("--- updated client list: ");
-                    for (k, v) in s.clone().clients.lock().unwrap().iter() {
-                        println!("--- {} -> {}", k, v);
-                    }
-
                     return ControlFlow::Break(());
                 }
                 Message::Pong(v) => {
                     println!(">>> {} sent pong with {:?}", who, v);
                 }
-                // You should never need to manually handle Message::Ping, as axum's websocket library
+                // You should never need to manually handle Message::Ping, as the websocket library
                 // will do so for you automagically by replying with Pong and copying the v according to
                 // spec. But if you need the contents of the pings you can see them here.
                 Message::Ping(v) => {
                     println!(">>> {} sent ping with {:?}", who, v);
                 }
+                // Raw frames are only ever written, never read
+                Message::Frame(_) => {}
             }
         }
 
@@ -317,9 +630,881 @@ async fn handle_socket(socket: WebSocket, who: SocketAddr, state: Arc<AppState>)
         _ = (&mut recv_task) => send_task.abort(),
     };
 
+    // However the connection ended, the client is no longer registered for anything
+    disconnect(&state, who);
+}
+
+/// Remove all registrations of `who`, and tell the publishers of the hashes it was viewing
+fn disconnect(state: &Arc<AppState>, who: ConnectionId) {
+    println!("--- {} removed from client list", who);
+    state.outbound.lock().unwrap().remove(&who);
+    state.codecs.lock().unwrap().remove(&who);
+
+    let mut published = Vec::new();
+    state.publishers.lock().unwrap().retain(|k, v| {
+        if v == &who {
+            published.push(k.clone());
+        }
+        v != &who
+    });
+    orphan(state, &published);
+
+    // Requests sent by the client are dropped, requests sent to it will never be answered
+    let mut unanswered = Vec::new();
+    state.requests.lock().unwrap().retain(|id, pending| {
+        if pending.to == who {
+            unanswered.push((id.clone(), pending.from));
+        }
+        pending.from != who && pending.to != who
+    });
+    for (id, from) in unanswered {
+        send_to(state, from, &Envelope::rpc_error(id, "viewer disconnected before responding"));
+    }
+
+    let mut hashes = Vec::new();
+    state.clients.lock().unwrap().retain(|k, v| {
+        if v.remove(&who) {
+            hashes.push(k.clone());
+        }
+        !v.is_empty()
+    });
+
+    for hash in &hashes {
+        notify_presence(state, hash, who, false);
+    }
+    orphan(state, &hashes);
+
+    println!("--- updated client list: ");
+    for (k, v) in state.clients.lock().unwrap().iter() {
+        println!("--- {} -> {:?}", k, v);
+    }
+}
+
+/// Act on a message received from `who`
+async fn handle_envelope(
+    state: &Arc<AppState>,
+    who: ConnectionId,
+    reply_tx: &mpsc::UnboundedSender<Envelope>,
+    envelope: Envelope,
+) {
+    match envelope {
+        // Register (or unregister) the client for the given hash
+        Envelope::Handshake { hash, connected } => {
+            if connected {
+                println!("--- {} added to client list", who);
+                state.clients.lock().unwrap().entry(hash.clone()).or_default().insert(who);
+                let _ = reply_tx.send(Envelope::Ack { hash: hash.clone() });
+                notify_presence(state, &hash, who, true);
+
+                // Replay the latest array, if it was sent before the client registered
+                replay(state, who, &hash, reply_tx).await;
+            } else {
+                println!("--- {} removed from client list", who);
+                let removed = {
+                    let mut clients = state.clients.lock().unwrap();
+                    let removed = clients.get_mut(&hash).is_some_and(|v| v.remove(&who));
+                    if clients.get(&hash).is_some_and(|v| v.is_empty()) {
+                        clients.remove(&hash);
+                    }
+                    removed
+                };
+                if removed {
+                    notify_presence(state, &hash, who, false);
+                    orphan(state, &[hash]);
+                }
+            }
+        }
+
+        // Headers are forwarded to the client registered for the hash, and mark the sender as
+        // the publisher of that hash
+        Envelope::Header(header) => {
+            state.publishers.lock().unwrap().insert(header.hash.clone(), who);
+            publish_header(state, who, header);
+        }
+
+        // Start or resume a chunked upload, and tell the sender which bytes were received
+        Envelope::UploadStart { upload_id, size, header } => {
+            let hash = header.hash.clone();
+            if size > state.max_payload_size {
+                let _ = reply_tx.send(Envelope::nack(
+                    Some(hash),
+                    DeliveryFailure::Oversize,
+                    format!("upload exceeds the limit of {} bytes", state.max_payload_size),
+                ));
+                return;
+            }
+
+            // A payload that does not match its header would be relayed as is
+            if header.byte_len().ok() != Some(size) {
+                let _ = reply_tx.send(Envelope::nack(
+                    Some(hash),
+                    DeliveryFailure::Malformed,
+                    format!(
+                        "upload of {size} bytes does not match an array of shape {:?} and dtype `{}`",
+                        header.shape, header.dtype
+                    ),
+                ));
+                return;
+            }
+            state.publishers.lock().unwrap().insert(hash.clone(), who);
+
+            let _ = reply_tx.send(start_upload(state, who, upload_id, header, size));
+        }
+
+        // Files are read in the background, so the connection keeps being served meanwhile
+        Envelope::Open { hash, path, series } => {
+            state.publishers.lock().unwrap().insert(hash.clone(), who);
+            let s = state.clone();
+            let reply_tx = reply_tx.clone();
+            tokio::spawn(async move {
+                let delivery = open_requested(&s, who, hash, PathBuf::from(path), series).await;
+                let _ = reply_tx.send(delivery);
+            });
+        }
+
+        // Mapped arrays are copied out of shared memory in the background as well
+        Envelope::Mapped { hash, path, shm, offset, shape, dtype } => {
+            state.publishers.lock().unwrap().insert(hash.clone(), who);
+            let (source, layout) = match mapped::locate(path, shm, shape, dtype, &state.mapped_dirs) {
+                Ok(located) => located,
+                Err(err) => {
+                    let _ = reply_tx.send(Envelope::nack(Some(hash), DeliveryFailure::Malformed, err));
+                    return;
+                }
+            };
+            let s = state.clone();
+            let reply_tx = reply_tx.clone();
+            tokio::spawn(async move {
+                let delivery = open_mapped(&s, who, hash, source, offset, layout).await;
+                let _ = reply_tx.send(delivery);
+            });
+        }
+
+        // View states and annotations travel between a viewer and the publisher of its hash
+        Envelope::ViewState { ref hash, .. } | Envelope::Annotation { ref hash, .. } => {
+            route(state, who, hash, &envelope);
+        }
+
+        // Payloads are compressed with the first of the codecs the client accepts that the
+        // server supports
+        Envelope::Negotiate { compression } => {
+            let codec = compression.iter().find_map(|name| Codec::from_name(name));
+            match codec {
+                Some(codec) => state.codecs.lock().unwrap().insert(who, codec),
+                None => state.codecs.lock().unwrap().remove(&who),
+            };
+            println!("--- {} negotiated compression {:?}", who, codec);
+            let _ = reply_tx.send(Envelope::Negotiated { compression: codec });
+        }
+
+        Envelope::Ping { id } => {
+            let _ = reply_tx.send(Envelope::Pong { id });
+        }
+
+        // Requests are forwarded to a viewer of the hash, and answered with an error if it does
+        // not respond in time
+        Envelope::Request { ref id, ref hash, .. } => {
+            let Some(viewer) = viewers(state, hash).into_iter().min_by_key(|v| v.0) else {
+                let _ = reply_tx.send(Envelope::rpc_error(id.clone(), format!("no viewer registered for hash `{hash}`")));
+                return;
+            };
+
+            {
+                let mut requests = state.requests.lock().unwrap();
+                if requests.contains_key(id) {
+                    let _ = reply_tx.send(Envelope::rpc_error(id.clone(), "a request with this id is already pending"));
+                    return;
+                }
+                requests.insert(id.clone(), PendingRequest { from: who, to: viewer });
+            }
+
+            println!("--- forwarding request `{}` from {} to {}", id, who, viewer);
+            send_to(state, viewer, &envelope);
+
+            let s = state.clone();
+            let id = id.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(s.request_timeout).await;
+                let pending = s.requests.lock().unwrap().remove(&id);
+                if let Some(pending) = pending {
+                    println!("--- request `{}` timed out", id);
+                    send_to(&s, pending.from, &Envelope::rpc_error(id, "viewer did not respond in time"));
+                }
+            });
+        }
+
+        // Responses are returned to the client that sent the request
+        Envelope::Response { ref id, .. } => {
+            let pending = state.requests.lock().unwrap().get(id).copied();
+            match pending {
+                Some(pending) if pending.to == who => {
+                    state.requests.lock().unwrap().remove(id);
+                    send_to(state, pending.from, &envelope);
+                }
+                _ => println!("--- {} responded to unknown request `{}`", who, id),
+            }
+        }
+
+        // Only the server sends these
+        Envelope::Welcome { .. }
+        | Envelope::Pong { .. }
+        | Envelope::Negotiated { .. }
+        | Envelope::Ack { .. }
+        | Envelope::Presence { .. }
+        | Envelope::Delivery { .. }
+        | Envelope::UploadStatus { .. }
+        | Envelope::Zarr { .. }
+        | Envelope::Resync { .. }
+        | Envelope::Error { .. } => {
+            println!("--- ignoring server-only message from {}", who);
+        }
+    }
+}
+
+/// The clients registered for `hash`
+fn viewers(state: &AppState, hash: &str) -> Vec<ConnectionId> {
+    state.clients.lock().unwrap()
+        .get(hash)
+        .map(|v| v.iter().copied().collect())
+        .unwrap_or_default()
+}
+
+/// Forward a message to the other end of `hash`: messages from a registered viewer go to the
+/// publisher of the hash, all other messages go to the registered viewers.
+fn route(state: &AppState, who: ConnectionId, hash: &str, envelope: &Envelope) {
+    let viewers = viewers(state, hash);
+    let to: Vec<ConnectionId> = if viewers.contains(&who) {
+        state.publishers.lock().unwrap().get(hash).copied().into_iter().collect()
+    } else {
+        viewers
+    };
+
+    if to.is_empty() {
+        println!("--- no client registered for hash `{}`", hash);
+        return;
+    }
+
+    let text = envelope.to_text();
+    for to in to {
+        send_text(state, to, text.clone());
+    }
+}
+
+/// Send a text message to a single client
+fn send_to(state: &AppState, to: ConnectionId, envelope: &Envelope) {
+    send_text(state, to, envelope.to_text());
+}
+
+/// Hand a text message to the text queue of `to`. The message is dropped if the queue is full,
+/// and the client resynchronized once it catches up.
+fn send_text(state: &AppState, to: ConnectionId, text: String) {
+    let Some(outbound) = state.outbound.lock().unwrap().get(&to).cloned() else {
+        println!("--- {} is no longer connected", to);
+        return;
+    };
+    if let Err(TrySendError::Full(_)) = outbound.text.try_send(text) {
+        outbound.skipped.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Tell the publisher of `hash` that viewer `who` connected or disconnected
+fn notify_presence(state: &AppState, hash: &str, who: ConnectionId, connected: bool) {
+    let Some(publisher) = state.publishers.lock().unwrap().get(hash).copied() else {
+        return;
+    };
+
+    let presence = Envelope::Presence {
+        hash: hash.to_owned(),
+        connection_id: who,
+        connected,
+        viewers: viewers(state, hash).len(),
+    };
+    send_to(state, publisher, &presence);
+}
+
+/// Whether a client publishes or views `hash`
+fn attended(state: &AppState, hash: &str) -> bool {
+    state.publishers.lock().unwrap().contains_key(hash) || state.clients.lock().unwrap().contains_key(hash)
+}
+
+/// Mark the latest arrays of the hashes that no one publishes or views anymore, and drop them
+/// once they have been left alone for `LATEST_EXPIRY`
+fn orphan(state: &Arc<AppState>, hashes: &[String]) {
+    let hashes: Vec<&String> = hashes.iter().filter(|hash| !attended(state, hash)).collect();
+    let now = Instant::now();
+    let mut orphaned = false;
+    {
+        let mut latest = state.latest.lock().unwrap();
+        for hash in hashes {
+            if let Some(latest) = latest.get_mut(hash) {
+                latest.orphaned = Some(now);
+                orphaned = true;
+            }
+        }
+    }
+
+    if orphaned {
+        let state = state.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(LATEST_EXPIRY).await;
+            expire_latest(&state);
+        });
+    }
+}
+
+/// Drop the latest arrays that have been left alone for longer than `LATEST_EXPIRY`
+fn expire_latest(state: &AppState) {
+    let now = Instant::now();
+    let expired = |latest: &Latest| latest.orphaned.is_some_and(|since| now.duration_since(since) >= LATEST_EXPIRY);
+    let candidates: Vec<String> = state.latest.lock().unwrap()
+        .iter()
+        .filter(|(_, latest)| expired(latest))
+        .map(|(hash, _)| hash.clone())
+        .collect();
+    let candidates: Vec<String> = candidates.into_iter().filter(|hash| !attended(state, hash)).collect();
+
+    // A viewer that registered in the meantime keeps the array
+    let mut latest = state.latest.lock().unwrap();
+    for hash in candidates {
+        if latest.get(&hash).is_some_and(expired) {
+            println!("--- dropping latest array for hash `{}`, no one has used it for a while", hash);
+            latest.remove(&hash);
+        }
+    }
+}
+
+/// All hashes `who` is registered for
+fn registered(state: &AppState, who: ConnectionId) -> Vec<String> {
+    state.clients.lock().unwrap()
+        .iter()
+        .filter(|(_, v)| v.contains(&who))
+        .map(|(k, _)| k.clone())
+        .collect()
+}
+
+/// Send `who` the latest array of `hash`: the Zarr store announcement and the header as
+/// replies, then the payload through its outbound queue, so the header arrives first
+async fn replay(state: &AppState, who: ConnectionId, hash: &str, reply_tx: &mpsc::UnboundedSender<Envelope>) {
+    let latest = state.latest.lock().unwrap().get_mut(hash).map(|latest| {
+        latest.orphaned = None;
+        latest.clone()
+    });
+    let latest = latest.unwrap_or_default();
+    if let Some(store) = &latest.store {
+        let _ = reply_tx.send(store_announcement(hash, store));
+    }
+    let (header, frame) = latest.first();
+    if let Some(header) = header {
+        let _ = reply_tx.send(Envelope::Header(header));
+    }
+    if let Some(frame) = frame {
+        println!("--- replaying latest payload for hash `{}` to {}", hash, who);
+        deliver(state, who, frame).await;
+    }
+}
+
+/// Act on a binary message received from `who`, returning the delivery report for the sender
+/// once there is something to report
+async fn handle_binary(state: &AppState, who: ConnectionId, data: Vec<u8>) -> Option<Envelope> {
+    let frame = match Frame::parse(data) {
+        // Compressed payloads are decompressed in the background, since that can take a while
+        Ok(frame) if frame.header.flags.compression().is_some() => {
+            let max_len = state.max_payload_size;
+            tokio::task::spawn_blocking(move || frame.decompress(max_len)).await.unwrap()
+        }
+        parsed => parsed,
+    };
+    let frame = match frame {
+        Ok(frame) => frame,
+        Err(err) => {
+            println!("--- {} sent malformed binary frame: {}", who, err);
+            return Some(Envelope::nack(None, DeliveryFailure::Malformed, err));
+        }
+    };
+
+    match frame.header.kind {
+        FrameKind::Array => Some(relay_frame(state, who, frame).await),
+        FrameKind::Chunk => handle_chunk(state, who, frame).await,
+        FrameKind::Npy | FrameKind::Npz | FrameKind::Nifti | FrameKind::Tiff => {
+            let (hash, kind, payload) = (frame.header.hash, frame.header.kind, frame.payload);
+            let max_len = state.max_payload_size;
+            let volume = read_blocking(move || match kind {
+                FrameKind::Npz => npy::read_npz(&payload, max_len).map_err(|err| err.to_string()),
+                FrameKind::Nifti => nifti::read(&payload, max_len).map_err(|err| err.to_string()),
+                FrameKind::Tiff => images::read_tiff(&payload, max_len).map_err(|err| err.to_string()),
+                _ => npy::read_npy(&payload).map_err(|err| err.to_string()),
+            })
+            .await;
+            match volume {
+                Ok(volume) => Some(publish_volume(state, who, hash, volume).await),
+                Err(err) => {
+                    println!("--- {} sent invalid file: {}", who, err);
+                    Some(Envelope::nack(Some(hash), DeliveryFailure::Malformed, err))
+                }
+            }
+        }
+    }
+}
+
+/// Read a volume in the background, since decoding a file can take a while. A reader that
+/// panics fails the read, rather than the task that waits for it.
+async fn read_blocking<E>(read: impl FnOnce() -> Result<Volume, E> + Send + 'static) -> Result<Volume, String>
+where
+    E: std::fmt::Display + Send + 'static,
+{
+    match tokio::task::spawn_blocking(read).await {
+        Ok(volume) => volume.map_err(|err| err.to_string()),
+        Err(err) => Err(format!("reading failed: {err}")),
+    }
+}
+
+/// Relay a volume read from a file, along with the header describing it
+async fn publish_volume(state: &AppState, who: ConnectionId, hash: String, volume: Volume) -> Envelope {
+    publish_header(state, who, volume.header(hash.clone()));
+    relay_frame(state, who, Frame::array(hash, volume.data)).await
+}
+
+/// The message announcing a Zarr store to the viewers of `hash`
+fn store_announcement(hash: &str, store: &zarr::Store) -> Envelope {
+    Envelope::Zarr {
+        hash: hash.to_owned(),
+        url: format!("/api/zarr/{hash}"),
+        zarr_format: store.zarr_format,
+        multiscales: store.multiscales.clone(),
+    }
+}
+
+/// Serve a Zarr store to the viewers of `hash`, in place of the latest array sent for it
+fn open_store(state: &AppState, who: ConnectionId, hash: String, path: &std::path::Path) -> Envelope {
+    let store = match zarr::Store::open(path) {
+        Ok(store) => store,
+        Err(err) => {
+            println!("--- {} could not open Zarr store: {}", who, err);
+            return Envelope::nack(Some(hash), DeliveryFailure::Malformed, err);
+        }
+    };
+
+    let announcement = store_announcement(&hash, &store);
+    state.latest.lock().unwrap().insert(hash.clone(), Latest {
+        store: Some(store),
+        ..Default::default()
+    });
+
+    let viewers = viewers(state, &hash);
+    if viewers.is_empty() {
+        println!("--- no client registered for hash `{}`, keeping Zarr store for later", hash);
+        return Envelope::nack(
+            Some(hash),
+            DeliveryFailure::NoViewer,
+            "no viewer is registered, the store is announced to viewers that register later",
+        );
+    }
+    route(state, who, &hash, &announcement);
+    Envelope::Delivery {
+        hash: Some(hash),
+        delivered: true,
+        viewers: viewers.len(),
+        reason: None,
+        message: None,
+    }
+}
+
+/// Read a file from the disk of the server and relay it to the viewers of `hash`
+async fn open_file(
+    state: &AppState,
+    who: ConnectionId,
+    hash: String,
+    path: PathBuf,
+    series: Option<String>,
+) -> Envelope {
+    println!(">>> {} opened `{}` for hash `{}`", who, path.display(), hash);
+    if zarr::is_store(&path) {
+        return open_store(state, who, hash, &path);
+    }
+
+    let max_len = state.max_payload_size;
+    let volume = read_blocking(move || volume::open(&path, series.as_deref(), max_len)).await;
+    match volume {
+        Ok(volume) => publish_volume(state, who, hash, volume).await,
+        Err(err) => {
+            println!("--- {} could not open file: {}", who, err);
+            Envelope::nack(Some(hash), DeliveryFailure::Malformed, err)
+        }
+    }
+}
+
+/// Open a file a client asked for, if it lies in one of the directories allowed with `--open-dir`
+async fn open_requested(
+    state: &AppState,
+    who: ConnectionId,
+    hash: String,
+    path: PathBuf,
+    series: Option<String>,
+) -> Envelope {
+    match volume::allowed_path(&path, &state.open_dirs) {
+        Ok(path) => open_file(state, who, hash, path, series).await,
+        Err(err) => {
+            println!("--- {} can not open file: {}", who, err);
+            Envelope::nack(Some(hash), DeliveryFailure::Malformed, err)
+        }
+    }
+}
+
+/// Read an array from a file or shared memory segment and relay it to the viewers of `hash`
+async fn open_mapped(
+    state: &AppState,
+    who: ConnectionId,
+    hash: String,
+    source: mapped::Source,
+    offset: u64,
+    layout: Option<mapped::Layout>,
+) -> Envelope {
+    println!(">>> {} mapped {} for hash `{}`", who, source, hash);
+    let volume = read_blocking(move || mapped::read(&source, offset, layout)).await;
+    match volume {
+        Ok(volume) => publish_volume(state, who, hash, volume).await,
+        Err(err) => {
+            println!("--- {} could not map array: {}", who, err);
+            Envelope::nack(Some(hash), DeliveryFailure::Malformed, err)
+        }
+    }
+}
+
+/// Start an upload, or resume the one with the same id, and report how far along it is. The
+/// uploads that are being reassembled take up at most `max_payload_size` bytes together.
+fn start_upload(state: &AppState, who: ConnectionId, upload_id: String, header: ArrayHeader, size: u64) -> Envelope {
+    // The array of a new upload is allocated without holding the lock
+    let mut new = None;
+    loop {
+        let mut uploads = state.uploads.lock().unwrap();
+        let now = Instant::now();
+        uploads.retain(|_, upload| !upload.is_expired(now));
+
+        if let Some(upload) = uploads.get(&upload_id) {
+            if upload.header != header || upload.size() != size {
+                return Envelope::error(
+                    ErrorCode::InvalidMessage,
+                    format!("upload `{upload_id}` was started for a different array"),
+                );
+            }
+            return Envelope::UploadStatus {
+                upload_id,
+                hash: header.hash,
+                size,
+                received: upload.received(),
+                next_offset: upload.next_offset(),
+            };
+        }
+
+        let pending: u64 = uploads.values().map(Upload::size).sum();
+        if pending + size > state.max_payload_size {
+            println!("--- {} can not start upload `{}`, {} bytes are pending", who, upload_id, pending);
+            return Envelope::nack(
+                Some(header.hash),
+                DeliveryFailure::Oversize,
+                format!("uploads in progress take up {pending} of {} bytes", state.max_payload_size),
+            );
+        }
+
+        if let Some(upload) = new.take() {
+            println!("--- {} started upload `{}` of {} bytes", who, upload_id, size);
+            uploads.insert(upload_id.clone(), upload);
+            continue;
+        }
+        drop(uploads);
+        new = Some(Upload::new(header.clone(), size));
+    }
+}
+
+/// Add a chunk to its upload, and relay the array once the upload is complete
+async fn handle_chunk(state: &AppState, who: ConnectionId, frame: Frame) -> Option<Envelope> {
+    let hash = frame.header.hash.clone();
+    let (chunk, offset) = match ChunkHeader::parse(&frame.payload) {
+        Ok(chunk) => chunk,
+        Err(err) => {
+            println!("--- {} sent malformed chunk: {}", who, err);
+            return Some(Envelope::nack(Some(hash), DeliveryFailure::Malformed, err));
+        }
+    };
+
+    let upload = {
+        let mut uploads = state.uploads.lock().unwrap();
+        let Some(upload) = uploads.get_mut(&chunk.upload_id) else {
+            println!("--- {} sent chunk for unknown upload `{}`", who, chunk.upload_id);
+            return Some(Envelope::nack(
+                Some(hash),
+                DeliveryFailure::UnknownUpload,
+                format!("upload `{}` was not started or has expired", chunk.upload_id),
+            ));
+        };
+
+        if upload.header.hash != hash {
+            return Some(Envelope::nack(
+                Some(hash),
+                DeliveryFailure::Malformed,
+                format!("upload `{}` is for hash `{}`", chunk.upload_id, upload.header.hash),
+            ));
+        }
+
+        if let Err(err) = upload.write(chunk.offset, &frame.payload[offset..]) {
+            println!("--- {} sent malformed chunk: {}", who, err);
+            return Some(Envelope::nack(Some(hash), DeliveryFailure::Malformed, err));
+        }
+
+        if !upload.is_complete() {
+            return None;
+        }
+        uploads.remove(&chunk.upload_id).unwrap()
+    };
+
+    println!("--- upload `{}` for hash `{}` is complete", chunk.upload_id, hash);
+
+    // The header of the array goes out ahead of its payload
+    publish_header(state, who, upload.header.clone());
+
+    Some(relay_frame(state, who, Frame::array(hash, upload.into_payload())).await)
+}
+
+/// Store the header of an array sent by `who`, and forward it to the viewers of its hash. The
+/// header of an array that is converted, gets statistics or a pyramid, is held back, the viewers
+/// are sent the rewritten header, or that of the coarsest level, instead once the payload
+/// arrives.
+fn publish_header(state: &AppState, who: ConnectionId, header: ArrayHeader) {
+    let hash = header.hash.clone();
+    {
+        let mut latest = state.latest.lock().unwrap();
+        let latest = latest.entry(hash.clone()).or_default();
+        latest.header = Some(header.clone());
+        latest.store = None;
+        latest.levels.clear();
+        latest.orphaned = None;
+    }
+    if !held_back(state, &header) {
+        route(state, who, &hash, &Envelope::Header(header));
+    }
+}
+
+/// Whether the header of an array is held back until its payload arrives and is processed
+fn held_back(state: &AppState, header: &ArrayHeader) -> bool {
+    needs_conversion(state, header) || needs_statistics(state, header) || needs_pyramid(state, header)
+}
+
+/// Whether the array described by `header` is converted before it is relayed
+fn needs_conversion(state: &AppState, header: &ArrayHeader) -> bool {
+    state.convert_dtypes && convert::applies(header)
+}
+
+/// Whether the statistics of the array described by `header` are computed. Big-endian arrays get
+/// none, unless they are converted first.
+fn needs_statistics(state: &AppState, header: &ArrayHeader) -> bool {
+    state.statistics && header.byteorder != Some(ByteOrder::Big)
+}
+
+/// Whether the array described by `header` is large enough to get a pyramid. Big-endian arrays
+/// are relayed as they are, unless they are converted first.
+fn needs_pyramid(state: &AppState, header: &ArrayHeader) -> bool {
+    let Some(max) = state.pyramid_size else {
+        return false;
+    };
+    header.byteorder != Some(ByteOrder::Big) && header.byte_len().is_ok_and(|len| len > max)
+}
+
+/// Convert an array to a dtype viewers handle well, storing its rewritten header, and returning
+/// it along with the converted payload
+async fn convert_frame(state: &AppState, mut header: ArrayHeader, frame: Frame) -> (ArrayHeader, Arc<Frame>) {
+    let hash = header.hash.clone();
+    let dtype: Result<DType, _> = header.dtype.parse();
+
+    // A payload that does not match its header is relayed as is
+    let dtype = match dtype {
+        Ok(dtype) if header.byte_len().ok() == Some(frame.payload.len() as u64) => dtype,
+        _ => return (header, Arc::new(frame)),
+    };
+
+    let from = header.dtype.clone();
+    let (header, data) = tokio::task::spawn_blocking(move || {
+        let data = convert::convert(&mut header, dtype, frame.payload);
+        (header, data)
+    })
+    .await
+    .unwrap();
+    if header.dtype != from {
+        println!("--- converted array for hash `{}` from {} to {}", hash, from, header.dtype);
+    }
+
+    state.latest.lock().unwrap().entry(hash.clone()).or_default().header = Some(header.clone());
+    (header, Arc::new(Frame::array(hash, data)))
+}
+
+/// Compute the statistics of an array, storing its header with the statistics attached, and
+/// returning that header
+async fn attach_statistics(state: &AppState, mut header: ArrayHeader, frame: Arc<Frame>) -> ArrayHeader {
+    let hash = header.hash.clone();
+    let dtype: Result<DType, _> = header.dtype.parse();
+
+    // A payload that does not match its header gets no statistics
+    let dtype = match dtype {
+        Ok(dtype) if header.byte_len().ok() == Some(frame.payload.len() as u64) => dtype,
+        _ => return header,
+    };
+
+    let shape = header.shape.clone();
+    let statistics = tokio::task::spawn_blocking(move || statistics::compute(&shape, dtype, &frame.payload))
+        .await
+        .unwrap();
+    println!("--- computed statistics of {} channel(s) for hash `{}`", statistics.len(), hash);
+
+    header.statistics = Some(statistics);
+    state.latest.lock().unwrap().entry(hash).or_default().header = Some(header.clone());
+    header
+}
+
+/// Build the pyramid of a large array and forward the header of its coarsest level to the
+/// viewers of its hash, returning the payload of that level
+async fn relay_pyramid(state: &AppState, who: ConnectionId, mut header: ArrayHeader, frame: Arc<Frame>) -> Arc<Frame> {
+    let hash = header.hash.clone();
+    let dtype: DType = header.dtype.parse().expect("only arrays of a known dtype get a pyramid");
+    let max = state.pyramid_size.expect("pyramids are enabled");
+
+    // A payload that does not match its header is relayed as is
+    if header.byte_len().ok() != Some(frame.payload.len() as u64) {
+        state.latest.lock().unwrap().entry(hash.clone()).or_default().frame = Some(frame.clone());
+        route(state, who, &hash, &Envelope::Header(header));
+        return frame;
+    }
+
+    let full = frame.clone();
+    let (header, levels) = tokio::task::spawn_blocking(move || {
+        let levels = pyramid::build(&mut header, dtype, &full.payload, max);
+        (header, levels)
+    })
+    .await
+    .unwrap();
+    println!("--- built a pyramid of {} levels for hash `{}`", levels.len() + 1, hash);
+
+    let levels: Vec<(ArrayHeader, Arc<Frame>)> = levels
+        .into_iter()
+        .map(|(header, data)| (header, Arc::new(Frame::array(hash.clone(), data))))
+        .collect();
+    let (coarsest, payload) = levels.last().cloned().unwrap_or((header.clone(), frame.clone()));
+    state.latest.lock().unwrap().insert(hash.clone(), Latest {
+        header: Some(header),
+        frame: Some(frame),
+        store: None,
+        levels,
+        orphaned: None,
+    });
+
+    route(state, who, &hash, &Envelope::Header(coarsest));
+    payload
+}
+
+/// Relay an array from `who` to the viewers of its hash, returning the delivery report for the
+/// sender
+async fn relay_frame(state: &AppState, who: ConnectionId, frame: Frame) -> Envelope {
+    let hash = frame.header.hash.clone();
+    if frame.header.payload_len > state.max_payload_size {
+        println!("--- {} sent {} bytes for hash `{}`, exceeding the limit", who, frame.header.payload_len, hash);
+        return Envelope::nack(
+            Some(hash),
+            DeliveryFailure::Oversize,
+            format!("payload exceeds the limit of {} bytes", state.max_payload_size),
+        );
+    }
+
+    // Arrays in a dtype viewers handle poorly are converted, and their held back header is
+    // rewritten to match, before the statistics of the array are attached to it
+    let header = {
+        let mut latest = state.latest.lock().unwrap();
+        let latest = latest.entry(hash.clone()).or_default();
+        latest.levels.clear();
+        latest.orphaned = None;
+        latest.header.clone()
+    };
+    let header = header.filter(|header| held_back(state, header));
+    let (header, frame) = match header {
+        Some(header) if needs_conversion(state, &header) => {
+            let (header, frame) = convert_frame(state, header, frame).await;
+            (Some(header), frame)
+        }
+        header => (header, Arc::new(frame)),
+    };
+    let header = match header {
+        Some(header) if needs_statistics(state, &header) => Some(attach_statistics(state, header, frame.clone()).await),
+        header => header,
+    };
+
+    // Keep the frame around for clients that register later. Large arrays are sent as the
+    // coarsest level of their pyramid, the finer levels are fetched by the viewers as needed.
+    let frame = match header {
+        Some(header) if needs_pyramid(state, &header) => relay_pyramid(state, who, header, frame).await,
+        header => {
+            state.latest.lock().unwrap().entry(hash.clone()).or_default().frame = Some(frame.clone());
+            if let Some(header) = header {
+                route(state, who, &hash, &Envelope::Header(header));
+            }
+            frame
+        }
+    };
+
+    // Hand the frame to the queues of the clients registered for the hash
+    let viewers = viewers(state, &hash);
+    if viewers.is_empty() {
+        println!("--- no client registered for hash `{}`, keeping payload for later", hash);
+        return Envelope::nack(
+            Some(hash),
+            DeliveryFailure::NoViewer,
+            "no viewer is registered, the payload is kept for viewers that register later",
+        );
+    }
+
+    let mut delivered = 0;
+    for viewer in viewers {
+        if deliver(state, viewer, frame.clone()).await {
+            delivered += 1;
+        }
+    }
+
+    if delivered == 0 {
+        return Envelope::nack(Some(hash), DeliveryFailure::Disconnected, "all viewers disconnected");
+    }
+
+    Envelope::Delivery {
+        hash: Some(hash),
+        delivered: true,
+        viewers: delivered,
+        reason: None,
+        message: None,
+    }
+}
+
+/// Hand a frame to the outbound queue of `to`, waiting if the queue is full. Returns whether
+/// the frame was queued.
+async fn deliver(state: &AppState, to: ConnectionId, frame: Arc<Frame>) -> bool {
+    let queue = state.outbound.lock().unwrap().get(&to).map(|outbound| outbound.frames.clone());
+    match queue {
+        Some(queue) => {
+            if queue.send(frame).await.is_err() {
+                println!("--- {} disconnected before the payload could be delivered", to);
+                return false;
+            }
+            true
+        }
+        None => {
+            println!("--- {} is no longer connected", to);
+            false
+        }
+    }
 }
 
 // Simple handler to ping the server
 async fn hello() -> impl IntoResponse {
     "Hello, Client!"
 }
+
+// Counters that can be used to monitor the server
+async fn metrics(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    Json(serde_json::json!({
+        "lag_events": state.lag_events.load(Ordering::Relaxed),
+    }))
+}
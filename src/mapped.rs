@@ -0,0 +1,224 @@
+//! Arrays handed over through memory shared with the server
+//!
+//! The Python runtime and the server almost always run on the same host, so a large array does
+//! not have to be copied through the websocket. Instead, the runtime leaves it in a file, such
+//! as one made with `np.save` or `np.memmap`, or in a POSIX shared memory segment, and sends a
+//! `mapped` message saying where to find it:
+//!
+//! ```not_rust
+//! {"type": "mapped", "hash": "dev", "path": "/tmp/volume.npy"}
+//! {"type": "mapped", "hash": "dev", "shm": "psm_21467_46075", "shape": [1, 512, 512, 512, 1], "dtype": "uint16"}
+//! ```
+//!
+//! The server maps the file or segment into memory and relays the array to the viewers. A file
+//! or segment holds an NPY file, unless `shape` and `dtype` are given, in which case it holds the
+//! raw elements, starting `offset` bytes in. The array is read before the delivery report is
+//! sent, after which the runtime can remove the file or unlink the segment.
+//!
+//! Only files in the temporary directory of the system, or in a directory allowed with
+//! `--mapped-dir`, are mapped. Segments are only mapped if they are named like the ones Python's
+//! `SharedMemory` creates, starting with `psm_`, and are owned by the user the server runs as.
+//! This keeps clients from having the server read the files and segments of other programs.
+
+use std::fmt;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+use memmap2::Mmap;
+
+use crate::dtype::{DType, UnknownDType};
+use crate::npy::{self, NpyError};
+use crate::volume::{self, Volume};
+
+/// Where a mapped array is found
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Source {
+    File(PathBuf),
+
+    /// Name of a POSIX shared memory segment, such as the `name` of a Python `SharedMemory`
+    Shm(String),
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::File(path) => write!(f, "`{}`", path.display()),
+            Source::Shm(name) => write!(f, "shared memory segment `{name}`"),
+        }
+    }
+}
+
+/// Start of the names Python gives the shared memory segments it creates
+const SHM_PREFIX: &str = "psm_";
+
+/// Shape and dtype of an array stored as raw elements
+pub type Layout = (Vec<usize>, DType);
+
+/// Reasons a mapped array can not be read
+#[derive(Debug)]
+pub enum MapError {
+    NoSource,
+    IncompleteLayout,
+    NotAllowed(PathBuf),
+    InvalidName(String),
+    ForeignShm(String),
+    UnknownDType(UnknownDType),
+    Io(std::io::Error),
+    Npy(NpyError),
+    OutOfBounds { offset: u64, len: u64, size: u64 },
+}
+
+impl fmt::Display for MapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MapError::NoSource => write!(f, "expected either a `path` or a `shm` segment"),
+            MapError::IncompleteLayout => write!(f, "expected both a `shape` and a `dtype`, or neither"),
+            MapError::NotAllowed(path) => write!(f, "`{}` is not in a directory arrays can be mapped from", path.display()),
+            MapError::InvalidName(name) => {
+                write!(f, "invalid shared memory segment name `{name}`, expected one starting with `{SHM_PREFIX}`")
+            }
+            MapError::ForeignShm(name) => write!(f, "shared memory segment `{name}` is owned by another user"),
+            MapError::UnknownDType(err) => err.fmt(f),
+            MapError::Io(err) => write!(f, "could not map array: {err}"),
+            MapError::Npy(err) => err.fmt(f),
+            MapError::OutOfBounds { offset, len, size } => write!(
+                f,
+                "array of {len} bytes at offset {offset} does not fit in the {size} mapped bytes"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for MapError {}
+
+impl From<std::io::Error> for MapError {
+    fn from(err: std::io::Error) -> Self {
+        MapError::Io(err)
+    }
+}
+
+impl From<UnknownDType> for MapError {
+    fn from(err: UnknownDType) -> Self {
+        MapError::UnknownDType(err)
+    }
+}
+
+impl From<NpyError> for MapError {
+    fn from(err: NpyError) -> Self {
+        MapError::Npy(err)
+    }
+}
+
+/// The file at `path`, with symbolic links resolved, if it lies in one of the `allowed`
+/// directories
+fn allowed_file(path: &Path, allowed: &[PathBuf]) -> Result<PathBuf, MapError> {
+    let path = path.canonicalize()?;
+    if volume::is_inside(&path, allowed) {
+        Ok(path)
+    } else {
+        Err(MapError::NotAllowed(path))
+    }
+}
+
+/// Whether `name` names a shared memory segment that may be mapped: a single path component,
+/// which may start with a slash, and starts with `SHM_PREFIX`
+fn valid_shm_name(name: &str) -> bool {
+    let name = name.strip_prefix('/').unwrap_or(name);
+    name.starts_with(SHM_PREFIX) && !name.contains(['/', '\0'])
+}
+
+/// The source and, for raw elements, the shape and dtype of an array, as given in a `mapped`
+/// message. Files are only mapped from the `allowed` directories.
+pub fn locate(
+    path: Option<String>,
+    shm: Option<String>,
+    shape: Option<Vec<usize>>,
+    dtype: Option<String>,
+    allowed: &[PathBuf],
+) -> Result<(Source, Option<Layout>), MapError> {
+    let source = match (path, shm) {
+        (Some(path), None) => Source::File(allowed_file(Path::new(&path), allowed)?),
+
+        (None, Some(name)) if valid_shm_name(&name) => Source::Shm(name),
+        (None, Some(name)) => return Err(MapError::InvalidName(name)),
+        _ => return Err(MapError::NoSource),
+    };
+    let layout = match (shape, dtype) {
+        (Some(shape), Some(dtype)) => Some((shape, dtype.parse()?)),
+        (None, None) => None,
+        _ => return Err(MapError::IncompleteLayout),
+    };
+    Ok((source, layout))
+}
+
+/// Open a POSIX shared memory segment, owned by the user the server runs as, for reading
+#[cfg(unix)]
+fn open_shm(name: &str) -> Result<File, MapError> {
+    use std::ffi::CString;
+    use std::os::unix::fs::MetadataExt;
+    use std::os::unix::io::FromRawFd;
+
+    // Python leaves out the leading slash of the names it hands out
+    let name = if name.starts_with('/') { name.to_owned() } else { format!("/{name}") };
+    let name = CString::new(name).map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))?;
+
+    // SAFETY: `name` is a valid C string, and the descriptor is owned by the returned file
+    let fd = unsafe { libc::shm_open(name.as_ptr(), libc::O_RDONLY, 0) };
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    let file = unsafe { File::from_raw_fd(fd) };
+
+    // SAFETY: `geteuid` can not fail
+    if file.metadata()?.uid() != unsafe { libc::geteuid() } {
+        return Err(MapError::ForeignShm(name.to_string_lossy().into_owned()));
+    }
+    Ok(file)
+}
+
+#[cfg(not(unix))]
+fn open_shm(_name: &str) -> Result<File, MapError> {
+    Err(MapError::Io(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "POSIX shared memory is not available on this platform",
+    )))
+}
+
+/// Map the file or shared memory segment holding an array
+fn map(source: &Source) -> Result<Mmap, MapError> {
+    let file = match source {
+        Source::File(path) => File::open(path)?,
+        Source::Shm(name) => open_shm(name)?,
+    };
+
+    // SAFETY: the mapping is only read from while the array is copied out of it, which the
+    // runtime waits for before it changes or removes the file or segment
+    Ok(unsafe { Mmap::map(&file)? })
+}
+
+/// Read an array from a file or shared memory segment: the raw elements of the given shape and
+/// dtype from `offset` on, or else the NPY file that starts at `offset`
+pub fn read(source: &Source, offset: u64, layout: Option<Layout>) -> Result<Volume, MapError> {
+    let map = map(source)?;
+    let size = map.len() as u64;
+    if offset > size {
+        return Err(MapError::OutOfBounds { offset, len: 0, size });
+    }
+    let data = &map[offset as usize..];
+
+    // Segments are rounded up to whole pages, so an NPY file in one can be followed by padding
+    let Some((shape, dtype)) = layout else {
+        return Ok(match source {
+            Source::File(_) => npy::read_npy(data)?,
+            Source::Shm(_) => npy::read_npy_prefix(data)?,
+        });
+    };
+
+    // Segments are rounded up to whole pages, so there can be more bytes than the array needs
+    let len = shape.iter().try_fold(dtype.itemsize(), |len, dim| len.checked_mul(*dim));
+    let len = match len {
+        Some(len) if len <= data.len() => len,
+        len => return Err(MapError::OutOfBounds { offset, len: len.map_or(u64::MAX, |len| len as u64), size }),
+    };
+    Ok(Volume::new(shape, dtype, data[..len].to_vec()))
+}
@@ -0,0 +1,389 @@
+//! JSON messages
+//!
+//! Every text frame exchanged over the websocket is a JSON object tagged with a `type`
+//! field, e.g.
+//!
+//! ```not_rust
+//! {"type": "handshake", "hash": "dev"}
+//! {"type": "header", "hash": "dev", "shape": [25, 1, 512, 512, 1], "dtype": "uint16"}
+//! ```
+//!
+//! The untagged handshake and header objects sent by older clients are still accepted.
+
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::compression::Codec;
+use crate::dtype::{ByteOrder, DType, UnknownDType};
+use crate::statistics::ChannelStatistics;
+
+/// Server-assigned id that identifies a single websocket connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(transparent)]
+pub struct ConnectionId(pub u64);
+
+impl fmt::Display for ConnectionId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "#{}", self.0)
+    }
+}
+
+/// Describes the array carried by the binary frame(s) sent under the same hash
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub struct ArrayHeader {
+    pub hash: String,
+    pub shape: Vec<usize>,
+    pub dtype: String,
+
+    /// Order of the bytes within every element, little-endian unless given
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub byteorder: Option<ByteOrder>,
+
+    /// Distance between the centers of neighbouring elements, for every axis of `shape`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub spacing: Option<Vec<f64>>,
+
+    /// Row-major matrix mapping voxel indices `(x, y, z, 1)` to world (RAS) coordinates in mm
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub affine: Option<[[f64; 4]; 4]>,
+
+    /// Window center and width to display the array with, e.g. from a DICOM series
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub window_center: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub window_width: Option<f64>,
+
+    /// Whether the array is a label map, whose values are ids rather than intensities
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub labels: bool,
+
+    /// Level of a multiresolution pyramid the array belongs to, where `0` is full resolution
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub level: Option<usize>,
+
+    /// Shapes of all levels of the pyramid, finest first
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub levels: Option<Vec<Vec<usize>>>,
+
+    /// Distribution of the values of every channel of the full-resolution array
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub statistics: Option<Vec<ChannelStatistics>>,
+}
+
+impl ArrayHeader {
+    pub fn new(hash: String, shape: Vec<usize>, dtype: DType) -> Self {
+        ArrayHeader {
+            hash,
+            shape,
+            dtype: dtype.name().to_owned(),
+            ..Default::default()
+        }
+    }
+
+    /// Number of bytes taken up by the array the header describes
+    pub fn byte_len(&self) -> Result<u64, SizeError> {
+        let dtype: DType = self.dtype.parse()?;
+        self.shape
+            .iter()
+            .try_fold(dtype.itemsize() as u64, |len, dim| len.checked_mul(*dim as u64))
+            .ok_or_else(|| SizeError::Overflow(self.shape.clone()))
+    }
+}
+
+/// Reasons the size of the array described by a header is not known
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SizeError {
+    UnknownDType(UnknownDType),
+    Overflow(Vec<usize>),
+}
+
+impl fmt::Display for SizeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SizeError::UnknownDType(err) => err.fmt(f),
+            SizeError::Overflow(shape) => write!(f, "array of shape {shape:?} is too large"),
+        }
+    }
+}
+
+impl std::error::Error for SizeError {}
+
+impl From<UnknownDType> for SizeError {
+    fn from(err: UnknownDType) -> Self {
+        SizeError::UnknownDType(err)
+    }
+}
+
+/// Error codes sent to clients in an [`Envelope::Error`] message
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// A text frame could not be parsed into a known message
+    InvalidMessage,
+}
+
+/// Reasons a binary payload could not be delivered, sent in an [`Envelope::Delivery`] message
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeliveryFailure {
+    /// No viewer is registered for the hash
+    NoViewer,
+    /// The payload exceeds the configured size limit
+    Oversize,
+    /// The binary frame had a malformed header
+    Malformed,
+    /// All viewers disconnected before the payload could be handed to them
+    Disconnected,
+    /// A chunk was sent for an upload that was never started, or has expired
+    UnknownUpload,
+}
+
+/// A message exchanged between the server, the Python runtime and the GUI
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Envelope {
+    /// Sent by the server to every client right after it connects
+    Welcome { connection_id: ConnectionId },
+
+    /// Sent by a GUI client to register for the arrays sent under `hash`
+    Handshake {
+        hash: String,
+        #[serde(default = "default_connected")]
+        connected: bool,
+    },
+
+    /// Describes the array in the binary frame that follows
+    Header(ArrayHeader),
+
+    /// Announces an array of `size` bytes that will be sent as chunks. Sending it again with
+    /// the same `upload_id`, e.g. after reconnecting, resumes the upload.
+    UploadStart {
+        upload_id: String,
+        size: u64,
+        #[serde(flatten)]
+        header: ArrayHeader,
+    },
+
+    /// Sent in reply to an `upload_start`, reporting how much of the upload was received
+    UploadStatus {
+        upload_id: String,
+        hash: String,
+        size: u64,
+        received: u64,
+        next_offset: u64,
+    },
+
+    /// Asks the server to read a file from its own disk, e.g. a NIfTI volume or a directory of
+    /// DICOM files, and send it to the viewers of `hash`. `series` selects a DICOM series by its
+    /// series instance UID.
+    Open {
+        hash: String,
+        path: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        series: Option<String>,
+    },
+
+    /// Asks the server to map an array left by the Python runtime in the file at `path`, or in
+    /// the POSIX shared memory segment `shm`, and send it to the viewers of `hash`. Without
+    /// `shape` and `dtype`, the file or segment holds an NPY file.
+    Mapped {
+        hash: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        path: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        shm: Option<String>,
+        #[serde(default)]
+        offset: u64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        shape: Option<Vec<usize>>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        dtype: Option<String>,
+    },
+
+    /// Announces a Zarr store to the viewers of `hash`, whose metadata and chunks can be
+    /// fetched from `url`
+    Zarr {
+        hash: String,
+        url: String,
+        zarr_format: u8,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        multiscales: Option<Value>,
+    },
+
+    /// Camera, slice or window settings of a viewer
+    ViewState { hash: String, state: Value },
+
+    /// An annotation made in, or sent to, a viewer
+    Annotation { hash: String, annotation: Value },
+
+    /// Sent by the Python runtime to ask a viewer of `hash` for information, such as the
+    /// current slice or window level. `id` correlates the request with its response.
+    Request {
+        id: String,
+        hash: String,
+        method: String,
+        #[serde(default)]
+        params: Value,
+    },
+
+    /// Answer to the request with the same `id`, sent by the viewer, or by the server if the
+    /// request could not be answered
+    Response {
+        id: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        result: Option<Value>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+
+    /// Liveness check, answered by the server with a `pong`
+    Ping {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
+
+    Pong {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
+
+    /// Sent by a client to have the payloads it is sent compressed with the first codec of
+    /// `compression` the server supports. An empty list turns compression off.
+    Negotiate {
+        #[serde(default)]
+        compression: Vec<String>,
+    },
+
+    /// Sent in reply to a `negotiate`, with the codec payloads are compressed with from now on
+    Negotiated { compression: Option<Codec> },
+
+    /// Confirms that a handshake for `hash` was accepted
+    Ack { hash: String },
+
+    /// Sent to the client that sent a binary payload, reporting to how many viewers it was
+    /// delivered, or why it was not
+    Delivery {
+        hash: Option<String>,
+        delivered: bool,
+        viewers: usize,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        reason: Option<DeliveryFailure>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        message: Option<String>,
+    },
+
+    /// Sent to the publisher of `hash` whenever a viewer connects or disconnects
+    Presence {
+        hash: String,
+        connection_id: ConnectionId,
+        connected: bool,
+        viewers: usize,
+    },
+
+    /// Sent to a client that fell behind and missed `skipped` messages, followed by the
+    /// latest state for the hashes it is registered for
+    Resync { skipped: u64 },
+
+    /// Sent to a client whose message was rejected
+    Error { code: ErrorCode, message: String },
+}
+
+fn default_connected() -> bool {
+    true
+}
+
+/// The handshake sent by older GUI clients, without a `type` tag
+#[derive(Debug, Deserialize)]
+struct GUIClientHandshake {
+    connected: bool,
+    hash: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Legacy {
+    Handshake(GUIClientHandshake),
+    Header(Box<ArrayHeader>),
+}
+
+impl From<Legacy> for Envelope {
+    fn from(legacy: Legacy) -> Self {
+        match legacy {
+            Legacy::Handshake(h) => Envelope::Handshake {
+                hash: h.hash,
+                connected: h.connected,
+            },
+            Legacy::Header(header) => Envelope::Header(*header),
+        }
+    }
+}
+
+/// Reasons a text frame can be rejected
+#[derive(Debug)]
+pub enum ParseError {
+    NotJson(serde_json::Error),
+    Invalid(serde_json::Error),
+    Untagged,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::NotJson(err) => write!(f, "message is not valid JSON: {err}"),
+            ParseError::Invalid(err) => write!(f, "invalid message: {err}"),
+            ParseError::Untagged => write!(f, "message has no `type` field"),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+impl Envelope {
+    /// Parse a text frame, falling back to the untagged messages of older clients.
+    pub fn parse(text: &str) -> Result<Self, ParseError> {
+        let value: Value = serde_json::from_str(text).map_err(ParseError::NotJson)?;
+
+        if value.get("type").is_some() {
+            return serde_json::from_value(value).map_err(ParseError::Invalid);
+        }
+
+        serde_json::from_value::<Legacy>(value)
+            .map(Envelope::from)
+            .map_err(|_| ParseError::Untagged)
+    }
+
+    /// Serialize the message into a JSON string.
+    pub fn to_text(&self) -> String {
+        serde_json::to_string(self).expect("messages are always serializable")
+    }
+
+    /// Shorthand for a report of a payload that was not delivered
+    pub fn nack(hash: Option<String>, reason: DeliveryFailure, message: impl fmt::Display) -> Self {
+        Envelope::Delivery {
+            hash,
+            delivered: false,
+            viewers: 0,
+            reason: Some(reason),
+            message: Some(message.to_string()),
+        }
+    }
+
+    /// Shorthand for a failed response to a request
+    pub fn rpc_error(id: String, error: impl fmt::Display) -> Self {
+        Envelope::Response {
+            id,
+            result: None,
+            error: Some(error.to_string()),
+        }
+    }
+
+    /// Shorthand for an error message
+    pub fn error(code: ErrorCode, message: impl fmt::Display) -> Self {
+        Envelope::Error {
+            code,
+            message: message.to_string(),
+        }
+    }
+}
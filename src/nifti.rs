@@ -0,0 +1,349 @@
+//! NIfTI-1 volumes
+//!
+//! Single-file `.nii` volumes, optionally gzipped as `.nii.gz`, are read into a 5D array of shape
+//! `(t, z, y, x, c)`, the layout the viewer expects of every array, with channels last rather
+//! than in the `(t, c, z, y, x)` order of e.g. ImageJ. Since NIfTI stores voxels with `x` varying
+//! fastest, this is the order the data is already in, apart from the components of vector-valued
+//! volumes, which are interleaved into the last axis. The voxel spacing and the affine that maps
+//! voxel indices to world coordinates are sent along in the header.
+//!
+//! See https://nifti.nimh.nih.gov/nifti-1
+
+use std::fmt;
+use std::io::Read;
+
+use flate2::read::GzDecoder;
+
+use crate::dtype::DType;
+use crate::volume::{interleave, Volume};
+
+/// Size of a NIfTI-1 header
+const HEADER_SIZE: usize = 348;
+
+/// Size of a NIfTI-2 header, recognized only to reject it with a clear error
+const NIFTI2_HEADER_SIZE: i32 = 540;
+
+/// Magic bytes that start every gzip stream
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+
+/// Reasons a NIfTI file can be rejected
+#[derive(Debug)]
+pub enum NiftiError {
+    NotNifti,
+    UnsupportedVersion,
+    SeparateHeader,
+    TooShort,
+    InvalidDimensions(Vec<i16>),
+    UnsupportedDatatype(i16),
+    LengthMismatch { expected: usize, actual: usize },
+    Gzip(std::io::Error),
+    TooLarge(u64),
+}
+
+impl fmt::Display for NiftiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NiftiError::NotNifti => write!(f, "not a NIfTI-1 file"),
+            NiftiError::UnsupportedVersion => write!(f, "NIfTI-2 files are not supported"),
+            NiftiError::SeparateHeader => {
+                write!(f, "NIfTI files with a separate .hdr/.img pair are not supported")
+            }
+            NiftiError::TooShort => write!(f, "NIfTI file is truncated"),
+            NiftiError::InvalidDimensions(dim) => write!(f, "invalid NIfTI dimensions {dim:?}"),
+            NiftiError::UnsupportedDatatype(code) => {
+                write!(f, "unsupported NIfTI datatype {code}")
+            }
+            NiftiError::LengthMismatch { expected, actual } => write!(
+                f,
+                "NIfTI header describes {expected} bytes of voxel data, but file holds {actual}"
+            ),
+            NiftiError::Gzip(err) => write!(f, "could not decompress NIfTI file: {err}"),
+            NiftiError::TooLarge(max_len) => write!(f, "NIfTI file exceeds the limit of {max_len} bytes"),
+        }
+    }
+}
+
+impl std::error::Error for NiftiError {}
+
+/// Whether `data` looks like a NIfTI-1 file, either plain or gzipped
+pub fn is_nifti(data: &[u8]) -> bool {
+    data.starts_with(GZIP_MAGIC) || data.get(344..348).is_some_and(|magic| magic == b"n+1\0")
+}
+
+/// Reads the little- or big-endian fields of a header
+struct Fields<'a> {
+    header: &'a [u8],
+    big_endian: bool,
+}
+
+impl Fields<'_> {
+    fn bytes<const N: usize>(&self, offset: usize) -> [u8; N] {
+        let mut bytes: [u8; N] = self.header[offset..offset + N].try_into().unwrap();
+        if self.big_endian {
+            bytes.reverse();
+        }
+        bytes
+    }
+
+    fn i16(&self, offset: usize) -> i16 {
+        i16::from_le_bytes(self.bytes(offset))
+    }
+
+    fn f32(&self, offset: usize) -> f64 {
+        f32::from_le_bytes(self.bytes(offset)) as f64
+    }
+
+    fn f32s<const N: usize>(&self, offset: usize) -> [f64; N] {
+        std::array::from_fn(|i| self.f32(offset + 4 * i))
+    }
+}
+
+/// Data type of a NIfTI `datatype` code
+fn datatype(code: i16) -> Result<DType, NiftiError> {
+    let dtype = match code {
+        2 => DType::Uint8,
+        4 => DType::Int16,
+        8 => DType::Int32,
+        16 => DType::Float32,
+        64 => DType::Float64,
+        256 => DType::Int8,
+        512 => DType::Uint16,
+        768 => DType::Uint32,
+        1024 => DType::Int64,
+        1280 => DType::Uint64,
+        other => return Err(NiftiError::UnsupportedDatatype(other)),
+    };
+    Ok(dtype)
+}
+
+/// Affine built from the quaternion of the `qform`, see `nifti1.h` method 2
+fn qform_affine(quatern: [f64; 3], offset: [f64; 3], pixdim: [f64; 3], qfac: f64) -> [[f64; 4]; 4] {
+    let [b, c, d] = quatern;
+    let a = (1.0 - (b * b + c * c + d * d)).max(0.0).sqrt();
+    let rotation = [
+        [a * a + b * b - c * c - d * d, 2.0 * (b * c - a * d), 2.0 * (b * d + a * c)],
+        [2.0 * (b * c + a * d), a * a + c * c - b * b - d * d, 2.0 * (c * d - a * b)],
+        [2.0 * (b * d - a * c), 2.0 * (c * d + a * b), a * a + d * d - c * c - b * b],
+    ];
+    let scale = [pixdim[0], pixdim[1], qfac * pixdim[2]];
+
+    let mut affine = [[0.0, 0.0, 0.0, 1.0]; 4];
+    for row in 0..3 {
+        for col in 0..3 {
+            affine[row][col] = rotation[row][col] * scale[col];
+        }
+        affine[row][3] = offset[row];
+    }
+    affine
+}
+
+/// Read a NIfTI-1 file, gunzipping it first if needed, but not beyond `max_len` bytes
+pub fn read(data: &[u8], max_len: u64) -> Result<Volume, NiftiError> {
+    if data.starts_with(GZIP_MAGIC) {
+        let mut plain = Vec::new();
+        GzDecoder::new(data).take(max_len + 1).read_to_end(&mut plain).map_err(NiftiError::Gzip)?;
+        if plain.len() as u64 > max_len {
+            return Err(NiftiError::TooLarge(max_len));
+        }
+        return read_nii(&plain);
+    }
+    read_nii(data)
+}
+
+/// Read an uncompressed NIfTI-1 file into a little-endian, C-ordered `(t, z, y, x, c)` array
+fn read_nii(data: &[u8]) -> Result<Volume, NiftiError> {
+    if data.len() < HEADER_SIZE {
+        return Err(NiftiError::TooShort);
+    }
+
+    // `sizeof_hdr` is always 348, which tells the byte order apart
+    let sizeof_hdr = [data[0], data[1], data[2], data[3]];
+    let big_endian = match (i32::from_le_bytes(sizeof_hdr), i32::from_be_bytes(sizeof_hdr)) {
+        (348, _) => false,
+        (_, 348) => true,
+        (NIFTI2_HEADER_SIZE, _) | (_, NIFTI2_HEADER_SIZE) => return Err(NiftiError::UnsupportedVersion),
+        _ => return Err(NiftiError::NotNifti),
+    };
+    match &data[344..348] {
+        b"n+1\0" => {}
+        b"ni1\0" => return Err(NiftiError::SeparateHeader),
+        _ => return Err(NiftiError::NotNifti),
+    }
+    let fields = Fields { header: &data[..HEADER_SIZE], big_endian };
+
+    let dim: [i16; 8] = std::array::from_fn(|i| fields.i16(40 + 2 * i));
+    let ndim = dim[0] as usize;
+    if !(1..=7).contains(&ndim) || dim[1..=ndim].iter().any(|d| *d < 1) {
+        return Err(NiftiError::InvalidDimensions(dim.to_vec()));
+    }
+    let size = |axis: usize| if axis <= ndim { dim[axis] as usize } else { 1 };
+    let (x, y, z, t) = (size(1), size(2), size(3), size(4));
+    let components = (5..=7).map(size).product::<usize>();
+
+    let dtype = datatype(fields.i16(70))?;
+    let pixdim: [f64; 8] = fields.f32s(76);
+    let vox_offset = (fields.f32(108) as usize).max(HEADER_SIZE);
+    let (slope, inter) = (fields.f32(112), fields.f32(116));
+
+    // Spatial units are stored in the lowest 3 bits of `xyzt_units`, converted here to mm
+    let mm = match data[123] & 0x07 {
+        1 => 1000.0,
+        3 => 0.001,
+        _ => 1.0,
+    };
+
+    let expected = [x, y, z, t, components]
+        .iter()
+        .try_fold(dtype.itemsize(), |len, size| len.checked_mul(*size))
+        .ok_or_else(|| NiftiError::InvalidDimensions(dim.to_vec()))?;
+    let actual = data.len().saturating_sub(vox_offset);
+    if actual < expected {
+        return Err(NiftiError::LengthMismatch { expected, actual });
+    }
+
+    let mut voxels = data[vox_offset..vox_offset + expected].to_vec();
+    if big_endian && dtype.itemsize() > 1 {
+        voxels.chunks_exact_mut(dtype.itemsize()).for_each(|item| item.reverse());
+    }
+    if components > 1 {
+        voxels = interleave(&voxels, components, dtype.itemsize());
+    }
+
+    // Scaling is applied here, so the viewer shows the calibrated values
+    let (dtype, voxels) = if slope != 0.0 && slope.is_finite() && (slope != 1.0 || inter != 0.0) {
+        let scaled = voxels
+            .chunks_exact(dtype.itemsize())
+            .flat_map(|item| ((dtype.value(item) * slope + inter) as f32).to_le_bytes())
+            .collect();
+        (DType::Float32, scaled)
+    } else {
+        (dtype, voxels)
+    };
+
+    let positive = |d: f64| if d > 0.0 && d.is_finite() { d } else { 1.0 };
+    let [dx, dy, dz] = [pixdim[1], pixdim[2], pixdim[3]].map(|d| positive(d) * mm);
+
+    let affine = if fields.i16(254) > 0 {
+        let mut affine = [[0.0, 0.0, 0.0, 1.0]; 4];
+        for (row, offset) in [280, 296, 312].into_iter().enumerate() {
+            affine[row] = fields.f32s(offset).map(|v| v * mm);
+        }
+        affine
+    } else if fields.i16(252) > 0 {
+        let qfac = if pixdim[0] < 0.0 { -1.0 } else { 1.0 };
+        let offset = fields.f32s::<3>(268).map(|v| v * mm);
+        qform_affine(fields.f32s(256), offset, [dx, dy, dz], qfac)
+    } else {
+        [[dx, 0.0, 0.0, 0.0], [0.0, dy, 0.0, 0.0], [0.0, 0.0, dz, 0.0], [0.0, 0.0, 0.0, 1.0]]
+    };
+
+    let mut volume = Volume::new(vec![t, z, y, x, components], dtype, voxels);
+    volume.spacing = Some(vec![positive(pixdim[4]), dz, dy, dx, 1.0]);
+    volume.affine = Some(affine);
+    Ok(volume)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::{write::GzEncoder, Compression};
+    use std::io::Write;
+
+    const MAX_LEN: u64 = 1 << 30;
+
+    /// A little-endian `.nii` file of the given dimensions and datatype, with a unit spacing and
+    /// neither a qform nor an sform
+    fn nii(dim: &[i16], datatype: i16, voxels: &[u8]) -> Vec<u8> {
+        let mut data = vec![0; 352];
+        data[0..4].copy_from_slice(&348i32.to_le_bytes());
+        put_i16(&mut data, 40, dim.len() as i16);
+        for (i, d) in dim.iter().enumerate() {
+            put_i16(&mut data, 42 + 2 * i, *d);
+        }
+        put_i16(&mut data, 70, datatype);
+        put_f32s(&mut data, 76, &[1.0; 8]);
+        put_f32s(&mut data, 108, &[352.0]);
+        data[344..348].copy_from_slice(b"n+1\0");
+        data.extend_from_slice(voxels);
+        data
+    }
+
+    fn put_i16(data: &mut [u8], offset: usize, value: i16) {
+        data[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn put_f32s(data: &mut [u8], offset: usize, values: &[f32]) {
+        for (i, value) in values.iter().enumerate() {
+            data[offset + 4 * i..offset + 4 * i + 4].copy_from_slice(&value.to_le_bytes());
+        }
+    }
+
+    fn f32s(data: &[u8]) -> Vec<f32> {
+        data.chunks_exact(4).map(|v| f32::from_le_bytes(v.try_into().unwrap())).collect()
+    }
+
+    #[test]
+    fn reads_dimensions_and_spacing() {
+        let mut data = nii(&[4, 3, 2], 2, &(0..24).collect::<Vec<u8>>());
+        put_f32s(&mut data, 76, &[1.0, 0.5, 0.75, 2.0]);
+        let volume = read(&data, MAX_LEN).unwrap();
+
+        assert_eq!((volume.shape, volume.dtype), (vec![1, 2, 3, 4, 1], DType::Uint8));
+        assert_eq!(volume.data, (0..24).collect::<Vec<u8>>());
+        assert_eq!(volume.spacing, Some(vec![1.0, 2.0, 0.75, 0.5, 1.0]));
+        assert_eq!(volume.affine.unwrap()[1], [0.0, 0.75, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn applies_scaling() {
+        let voxels: Vec<u8> = [1i16, 2, -3].iter().flat_map(|v| v.to_le_bytes()).collect();
+        let mut data = nii(&[3], 4, &voxels);
+        let volume = read(&data, MAX_LEN).unwrap();
+        assert_eq!((volume.dtype, volume.data), (DType::Int16, voxels));
+
+        put_f32s(&mut data, 112, &[2.0, 0.5]);
+        let volume = read(&data, MAX_LEN).unwrap();
+        assert_eq!(volume.dtype, DType::Float32);
+        assert_eq!(f32s(&volume.data), [2.5, 4.5, -5.5]);
+    }
+
+    #[test]
+    fn prefers_sform_over_qform() {
+        let mut data = nii(&[2, 2, 2], 2, &[0; 8]);
+        put_f32s(&mut data, 76, &[-1.0, 2.0, 3.0, 4.0]);
+
+        // A rotation of 180 degrees around z, flipped along z by the negative qfac
+        put_i16(&mut data, 252, 1);
+        put_f32s(&mut data, 256, &[0.0, 0.0, 1.0, 10.0, 20.0, 30.0]);
+        let qform = read(&data, MAX_LEN).unwrap().affine.unwrap();
+        assert_eq!(
+            qform,
+            [[-2.0, 0.0, 0.0, 10.0], [0.0, -3.0, 0.0, 20.0], [0.0, 0.0, -4.0, 30.0], [0.0, 0.0, 0.0, 1.0]]
+        );
+
+        put_i16(&mut data, 254, 1);
+        put_f32s(&mut data, 280, &[1.0, 0.0, 0.0, -1.0, 0.0, 1.0, 0.0, -2.0, 0.0, 0.0, 1.0, -3.0]);
+        let sform = read(&data, MAX_LEN).unwrap().affine.unwrap();
+        assert_eq!(
+            sform,
+            [[1.0, 0.0, 0.0, -1.0], [0.0, 1.0, 0.0, -2.0], [0.0, 0.0, 1.0, -3.0], [0.0, 0.0, 0.0, 1.0]]
+        );
+    }
+
+    #[test]
+    fn rejects_truncated_files() {
+        let data = nii(&[4, 4], 512, &[0; 32]);
+        assert!(matches!(read(&data[..200], MAX_LEN), Err(NiftiError::TooShort)));
+        assert!(matches!(
+            read(&data[..data.len() - 1], MAX_LEN),
+            Err(NiftiError::LengthMismatch { expected: 32, actual: 31 })
+        ));
+
+        let mut gzipped = GzEncoder::new(Vec::new(), Compression::default());
+        gzipped.write_all(&data).unwrap();
+        let gzipped = gzipped.finish().unwrap();
+        assert!(read(&gzipped, data.len() as u64).is_ok());
+        assert!(matches!(read(&gzipped, 100), Err(NiftiError::TooLarge(100))));
+        assert!(matches!(read(&gzipped[..gzipped.len() / 2], MAX_LEN), Err(NiftiError::Gzip(_))));
+    }
+}
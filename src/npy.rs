@@ -0,0 +1,378 @@
+//! NumPy `.npy` and `.npz` files
+//!
+//! Arrays saved with `np.save` or `np.savez` can be sent as they are, instead of as a JSON
+//! header followed by the raw bytes. The NPY header is parsed for the dtype, shape, memory
+//! order and endianness, and the data is normalized to a little-endian, C-ordered array before
+//! it is relayed to the viewers.
+//!
+//! See https://numpy.org/doc/stable/reference/generated/numpy.lib.format.html
+
+use std::fmt;
+use std::io::{Cursor, Read};
+
+use crate::dtype::DType;
+use crate::volume::Volume;
+
+/// Magic bytes that start every NPY file
+pub const NPY_MAGIC: &[u8] = b"\x93NUMPY";
+
+/// Magic bytes that start every NPZ (zip) file
+pub const NPZ_MAGIC: &[u8] = b"PK\x03\x04";
+
+/// Reasons an NPY or NPZ file can be rejected
+#[derive(Debug)]
+pub enum NpyError {
+    BadMagic,
+    UnsupportedVersion(u8, u8),
+    TooShort,
+    InvalidHeader(String),
+    UnsupportedDescr(String),
+    LengthMismatch { expected: usize, actual: usize },
+    Zip(zip::result::ZipError),
+    Io(std::io::Error),
+    EmptyArchive,
+    TooLarge(u64),
+}
+
+impl fmt::Display for NpyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NpyError::BadMagic => write!(f, "not an NPY file"),
+            NpyError::UnsupportedVersion(major, minor) => {
+                write!(f, "unsupported NPY version {major}.{minor}")
+            }
+            NpyError::TooShort => write!(f, "NPY file is truncated"),
+            NpyError::InvalidHeader(header) => write!(f, "invalid NPY header `{header}`"),
+            NpyError::UnsupportedDescr(descr) => write!(f, "unsupported NPY dtype `{descr}`"),
+            NpyError::LengthMismatch { expected, actual } => write!(
+                f,
+                "NPY header describes {expected} bytes, but file holds {actual}"
+            ),
+            NpyError::Zip(err) => write!(f, "invalid NPZ file: {err}"),
+            NpyError::Io(err) => write!(f, "could not read NPZ file: {err}"),
+            NpyError::EmptyArchive => write!(f, "NPZ file holds no arrays"),
+            NpyError::TooLarge(max_len) => write!(f, "array exceeds the limit of {max_len} bytes"),
+        }
+    }
+}
+
+impl std::error::Error for NpyError {}
+
+impl From<zip::result::ZipError> for NpyError {
+    fn from(err: zip::result::ZipError) -> Self {
+        NpyError::Zip(err)
+    }
+}
+
+impl From<std::io::Error> for NpyError {
+    fn from(err: std::io::Error) -> Self {
+        NpyError::Io(err)
+    }
+}
+
+/// Byte order of the elements in an NPY file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Endian {
+    Little,
+    Big,
+}
+
+/// Parse an NPY `descr` such as `<u2`, `>f8` or `|b1`
+fn parse_descr(descr: &str) -> Result<(DType, Endian), NpyError> {
+    let unsupported = || NpyError::UnsupportedDescr(descr.to_owned());
+
+    let mut chars = descr.chars();
+    let endian = match chars.next().ok_or_else(unsupported)? {
+        '<' | '|' => Endian::Little,
+        '>' => Endian::Big,
+        '=' if cfg!(target_endian = "little") => Endian::Little,
+        '=' => Endian::Big,
+        _ => return Err(unsupported()),
+    };
+
+    let dtype = match chars.as_str() {
+        "b1" | "?" => DType::Bool,
+        "i1" => DType::Int8,
+        "u1" => DType::Uint8,
+        "i2" => DType::Int16,
+        "u2" => DType::Uint16,
+        "i4" => DType::Int32,
+        "u4" => DType::Uint32,
+        "i8" => DType::Int64,
+        "u8" => DType::Uint64,
+        "f2" => DType::Float16,
+        "f4" => DType::Float32,
+        "f8" => DType::Float64,
+        _ => return Err(unsupported()),
+    };
+
+    Ok((dtype, endian))
+}
+
+/// The literal following `'key':` in the Python dict of an NPY header
+fn dict_value<'a>(header: &'a str, key: &str) -> Option<&'a str> {
+    let start = header.find(&format!("'{key}'"))? + key.len() + 2;
+    let rest = header[start..].trim_start().strip_prefix(':')?;
+    Some(rest.trim_start())
+}
+
+/// Parse the Python dict of an NPY header into its dtype, endianness, memory order and shape
+fn parse_header(header: &str) -> Result<(DType, Endian, bool, Vec<usize>), NpyError> {
+    let invalid = || NpyError::InvalidHeader(header.trim().to_owned());
+
+    let descr = dict_value(header, "descr").ok_or_else(invalid)?;
+    let quote = descr.chars().next().filter(|c| *c == '\'' || *c == '"').ok_or_else(invalid)?;
+    let descr = &descr[1..descr[1..].find(quote).ok_or_else(invalid)? + 1];
+    let (dtype, endian) = parse_descr(descr)?;
+
+    let fortran_order = dict_value(header, "fortran_order").ok_or_else(invalid)?;
+    let fortran_order = if fortran_order.starts_with("True") {
+        true
+    } else if fortran_order.starts_with("False") {
+        false
+    } else {
+        return Err(invalid());
+    };
+
+    let shape = dict_value(header, "shape").ok_or_else(invalid)?;
+    let shape = shape.strip_prefix('(').ok_or_else(invalid)?;
+    let shape = &shape[..shape.find(')').ok_or_else(invalid)?];
+    let shape = shape
+        .split(',')
+        .map(str::trim)
+        .filter(|dim| !dim.is_empty())
+        .map(|dim| dim.trim_end_matches('L').parse::<usize>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| invalid())?;
+
+    Ok((dtype, endian, fortran_order, shape))
+}
+
+/// Reverse the byte order of every element in place
+fn byte_swap(data: &mut [u8], itemsize: usize) {
+    if itemsize > 1 {
+        data.chunks_exact_mut(itemsize).for_each(|item| item.reverse());
+    }
+}
+
+/// Reorder an array stored in Fortran (column-major) order into C (row-major) order
+fn fortran_to_c(data: &[u8], shape: &[usize], itemsize: usize) -> Vec<u8> {
+    let count: usize = shape.iter().product();
+    if shape.len() < 2 || count == 0 {
+        return data.to_vec();
+    }
+
+    // Stride of every axis in the Fortran-ordered source, in elements
+    let mut strides = vec![1; shape.len()];
+    for axis in 1..shape.len() {
+        strides[axis] = strides[axis - 1] * shape[axis - 1];
+    }
+
+    // Walk the destination in C order, keeping track of the source offset
+    let mut out = Vec::with_capacity(data.len());
+    let mut index = vec![0; shape.len()];
+    let mut src = 0;
+    for _ in 0..count {
+        out.extend_from_slice(&data[src * itemsize..(src + 1) * itemsize]);
+
+        for axis in (0..shape.len()).rev() {
+            index[axis] += 1;
+            src += strides[axis];
+            if index[axis] < shape[axis] {
+                break;
+            }
+            src -= strides[axis] * shape[axis];
+            index[axis] = 0;
+        }
+    }
+    out
+}
+
+/// Read an NPY file into a little-endian, C-ordered array
+pub fn read_npy(data: &[u8]) -> Result<Volume, NpyError> {
+    parse_npy(data, false)
+}
+
+/// Read the NPY file at the start of `data`, ignoring the bytes that follow it
+pub fn read_npy_prefix(data: &[u8]) -> Result<Volume, NpyError> {
+    parse_npy(data, true)
+}
+
+/// Read an NPY file, which takes up all of `data` unless `trailing` bytes are allowed
+fn parse_npy(data: &[u8], trailing: bool) -> Result<Volume, NpyError> {
+    if !data.starts_with(NPY_MAGIC) {
+        return Err(NpyError::BadMagic);
+    }
+    if data.len() < 10 {
+        return Err(NpyError::TooShort);
+    }
+
+    // Version 1 uses a 2-byte header length, versions 2 and 3 a 4-byte one
+    let (major, minor) = (data[6], data[7]);
+    let (header_len, header_start) = match major {
+        1 => (u16::from_le_bytes([data[8], data[9]]) as usize, 10),
+        2 | 3 if data.len() >= 12 => (
+            u32::from_le_bytes([data[8], data[9], data[10], data[11]]) as usize,
+            12,
+        ),
+        2 | 3 => return Err(NpyError::TooShort),
+        _ => return Err(NpyError::UnsupportedVersion(major, minor)),
+    };
+
+    let data_start = header_start + header_len;
+    if data.len() < data_start {
+        return Err(NpyError::TooShort);
+    }
+    let header = String::from_utf8_lossy(&data[header_start..data_start]);
+    let (dtype, endian, fortran_order, shape) = parse_header(&header)?;
+
+    let expected = shape
+        .iter()
+        .try_fold(dtype.itemsize(), |len, dim| len.checked_mul(*dim))
+        .ok_or_else(|| NpyError::InvalidHeader(header.trim().to_owned()))?;
+    let actual = data.len() - data_start;
+    if actual < expected || (actual > expected && !trailing) {
+        return Err(NpyError::LengthMismatch { expected, actual });
+    }
+
+    let body = &data[data_start..data_start + expected];
+    let mut data = if fortran_order {
+        fortran_to_c(body, &shape, dtype.itemsize())
+    } else {
+        body.to_vec()
+    };
+    if endian == Endian::Big {
+        byte_swap(&mut data, dtype.itemsize());
+    }
+
+    Ok(Volume::new(shape, dtype, data))
+}
+
+/// Read the first array of an NPZ file, preferring the one `np.savez` names `arr_0`, refusing
+/// arrays that take up more than `max_len` bytes
+pub fn read_npz(data: &[u8], max_len: u64) -> Result<Volume, NpyError> {
+    let mut archive = zip::ZipArchive::new(Cursor::new(data))?;
+
+    let mut names: Vec<String> = archive
+        .file_names()
+        .filter(|name| name.ends_with(".npy"))
+        .map(str::to_owned)
+        .collect();
+    names.sort_by_key(|name| (name != "arr_0.npy", name.clone()));
+    let name = names.first().ok_or(NpyError::EmptyArchive)?;
+
+    // The size in the zip header can not be trusted, so the entry is read up to the limit
+    let file = archive.by_name(name)?;
+    let mut npy = Vec::new();
+    file.take(max_len + 1).read_to_end(&mut npy)?;
+    if npy.len() as u64 > max_len {
+        return Err(NpyError::TooLarge(max_len));
+    }
+    read_npy(&npy)
+}
+
+/// Read an NPY or NPZ file, telling them apart by their magic bytes
+pub fn read(data: &[u8], max_len: u64) -> Result<Volume, NpyError> {
+    if data.starts_with(NPZ_MAGIC) {
+        read_npz(data, max_len)
+    } else {
+        read_npy(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A version 1.0 NPY file with the given header fields and body
+    fn npy(descr: &str, fortran_order: bool, shape: &str, body: &[u8]) -> Vec<u8> {
+        let fortran_order = if fortran_order { "True" } else { "False" };
+        let mut header = format!("{{'descr': '{descr}', 'fortran_order': {fortran_order}, 'shape': {shape}, }}");
+        while (10 + header.len() + 1) % 64 != 0 {
+            header.push(' ');
+        }
+        header.push('\n');
+
+        let mut data = NPY_MAGIC.to_vec();
+        data.extend_from_slice(&[1, 0]);
+        data.extend_from_slice(&(header.len() as u16).to_le_bytes());
+        data.extend_from_slice(header.as_bytes());
+        data.extend_from_slice(body);
+        data
+    }
+
+    #[test]
+    fn reorders_fortran_arrays() {
+        // [[0, 1, 2], [3, 4, 5]] stored column by column
+        let data = fortran_to_c(&[0, 3, 1, 4, 2, 5], &[2, 3], 1);
+        assert_eq!(data, [0, 1, 2, 3, 4, 5]);
+
+        // The element at (i, j, k) holds 100 * i + 10 * j + k
+        let shape = [2, 3, 4];
+        let mut fortran = Vec::new();
+        for k in 0..4u16 {
+            for j in 0..3u16 {
+                for i in 0..2u16 {
+                    fortran.extend_from_slice(&(100 * i + 10 * j + k).to_le_bytes());
+                }
+            }
+        }
+        let c: Vec<u16> = fortran_to_c(&fortran, &shape, 2)
+            .chunks_exact(2)
+            .map(|item| u16::from_le_bytes([item[0], item[1]]))
+            .collect();
+        let mut expected = Vec::new();
+        for i in 0..2u16 {
+            for j in 0..3u16 {
+                expected.extend((0..4u16).map(|k| 100 * i + 10 * j + k));
+            }
+        }
+        assert_eq!(c, expected);
+    }
+
+    #[test]
+    fn leaves_one_dimensional_and_empty_arrays() {
+        assert_eq!(fortran_to_c(&[1, 2, 3], &[3], 1), [1, 2, 3]);
+        assert_eq!(fortran_to_c(&[], &[0, 3], 1), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn reads_big_endian_arrays() {
+        let body: Vec<u8> = [1u16, 2, 0x0304].iter().flat_map(|v| v.to_be_bytes()).collect();
+        let array = read_npy(&npy(">u2", false, "(3,)", &body)).unwrap();
+        assert_eq!((array.shape, array.dtype), (vec![3], DType::Uint16));
+        assert_eq!(array.data, [1, 0, 2, 0, 4, 3]);
+    }
+
+    #[test]
+    fn reads_big_endian_fortran_arrays() {
+        // [[1.0, 2.0], [3.0, 4.0]] stored column by column
+        let body: Vec<u8> = [1.0f32, 3.0, 2.0, 4.0].iter().flat_map(|v| v.to_be_bytes()).collect();
+        let array = read_npy(&npy(">f4", true, "(2, 2)", &body)).unwrap();
+        let expected: Vec<u8> = [1.0f32, 2.0, 3.0, 4.0].iter().flat_map(|v| v.to_le_bytes()).collect();
+        assert_eq!(array.data, expected);
+    }
+
+    #[test]
+    fn rejects_length_mismatch_and_overflow() {
+        let err = read_npy(&npy("<u2", false, "(3,)", &[0; 4])).unwrap_err();
+        assert!(matches!(err, NpyError::LengthMismatch { expected: 6, actual: 4 }));
+
+        let shape = format!("({}, {})", usize::MAX, 2);
+        let err = read_npy(&npy("<u1", false, &shape, &[])).unwrap_err();
+        assert!(matches!(err, NpyError::InvalidHeader(_)));
+    }
+
+    #[test]
+    fn reads_arrays_followed_by_padding() {
+        let mut data = npy("<u2", false, "(3,)", &[1, 0, 2, 0, 3, 0]);
+        data.resize(4096, 0);
+        let array = read_npy_prefix(&data).unwrap();
+        assert_eq!((array.shape, array.data), (vec![3], vec![1, 0, 2, 0, 3, 0]));
+
+        let err = read_npy(&data).unwrap_err();
+        assert!(matches!(err, NpyError::LengthMismatch { expected: 6, .. }));
+        let err = read_npy_prefix(&npy("<u2", false, "(3,)", &[0; 4])).unwrap_err();
+        assert!(matches!(err, NpyError::LengthMismatch { expected: 6, actual: 4 }));
+    }
+}
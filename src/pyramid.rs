@@ -0,0 +1,278 @@
+//! Multiresolution pyramids
+//!
+//! Large arrays can be downsampled into a pyramid of levels, each half the size of the previous
+//! one along the spatial axes: `z`, `y` and `x` of a 5D `(t, z, y, x, c)` array, or every axis
+//! of an array of another rank, except for a last axis of up to 4 channels, as in `(y, x, 3)`.
+//! Viewers are sent the coarsest level first, so they become interactive right away, and fetch
+//! the finer levels as they need them.
+//!
+//! Intensities are averaged, while label maps, and boolean masks, take the most frequent value
+//! of every 2×2×2 block, so no labels are made up along the boundaries between regions.
+
+use crate::dtype::DType;
+use crate::message::ArrayHeader;
+
+/// How the elements of a block are combined into one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reduction {
+    Mean,
+    Mode,
+}
+
+impl Reduction {
+    /// The reduction that suits an array
+    pub fn of(header: &ArrayHeader, dtype: DType) -> Self {
+        if header.labels || dtype == DType::Bool {
+            Reduction::Mode
+        } else {
+            Reduction::Mean
+        }
+    }
+}
+
+/// Maximum size of the last axis of an array that is not 5D for it to hold channels
+const MAX_CHANNELS: usize = 4;
+
+/// Which axes of an array of shape `shape` are halved: its spatial ones
+fn halved_axes(shape: &[usize]) -> Vec<bool> {
+    match shape {
+        [_, _, _, _, _] => vec![false, true, true, true, false],
+        [.., c] if shape.len() >= 3 && *c <= MAX_CHANNELS => {
+            let mut halved = vec![true; shape.len()];
+            halved[shape.len() - 1] = false;
+            halved
+        }
+        _ => vec![true; shape.len()],
+    }
+}
+
+/// Halve an array along the given axes, rounding odd sizes up
+fn downsample(shape: &[usize], dtype: DType, data: &[u8], halved: &[bool], reduction: Reduction) -> (Vec<usize>, Vec<u8>) {
+    let itemsize = dtype.itemsize();
+    let out_shape: Vec<usize> = shape
+        .iter()
+        .zip(halved)
+        .map(|(size, halved)| if *halved { size.div_ceil(2) } else { *size })
+        .collect();
+
+    // Stride of every axis of the input, in elements
+    let mut strides = vec![1; shape.len()];
+    for axis in (0..shape.len().saturating_sub(1)).rev() {
+        strides[axis] = strides[axis + 1] * shape[axis + 1];
+    }
+
+    let count: usize = out_shape.iter().product();
+    let mut out = Vec::with_capacity(count * itemsize);
+    let mut index = vec![0; shape.len()];
+    let mut block = Vec::with_capacity(1 << shape.len());
+    for _ in 0..count {
+        // Offsets of the elements of the block that maps onto this output element
+        block.clear();
+        block.push(0);
+        for axis in 0..shape.len() {
+            let start = if halved[axis] { index[axis] * 2 } else { index[axis] };
+            for item in block.iter_mut() {
+                *item += start * strides[axis];
+            }
+            if halved[axis] && start + 1 < shape[axis] {
+                for i in 0..block.len() {
+                    block.push(block[i] + strides[axis]);
+                }
+            }
+        }
+
+        let item = |offset: usize| &data[offset * itemsize..(offset + 1) * itemsize];
+        match reduction {
+            Reduction::Mean => {
+                let sum: f64 = block.iter().map(|offset| dtype.value(item(*offset))).sum();
+                let mean = sum / block.len() as f64;
+                match dtype {
+                    DType::Float16 | DType::Float32 | DType::Float64 => dtype.push(&mut out, mean),
+                    _ => dtype.push(&mut out, mean.round()),
+                }
+            }
+            Reduction::Mode => {
+                let mode = block
+                    .iter()
+                    .max_by_key(|a| block.iter().filter(|b| item(**a) == item(**b)).count())
+                    .unwrap();
+                out.extend_from_slice(item(*mode));
+            }
+        }
+
+        for axis in (0..shape.len()).rev() {
+            index[axis] += 1;
+            if index[axis] < out_shape[axis] {
+                break;
+            }
+            index[axis] = 0;
+        }
+    }
+
+    (out_shape, out)
+}
+
+/// Header of a level downsampled by `factors` along every axis, with its spacing and affine
+/// scaled to match
+fn level_header(base: &ArrayHeader, shape: Vec<usize>, factors: &[f64]) -> ArrayHeader {
+    let spacing = base
+        .spacing
+        .as_ref()
+        .map(|spacing| spacing.iter().zip(factors).map(|(d, f)| d * f).collect());
+
+    // The columns of the affine belong to the x, y and z axes of a 5D array. The center of a
+    // coarse voxel lies halfway across the block of fine voxels it replaces.
+    let affine = match base.affine {
+        Some(mut affine) if base.shape.len() == 5 => {
+            for (column, axis) in [(0, 3), (1, 2), (2, 1)] {
+                let f = factors[axis];
+                for row in affine.iter_mut().take(3) {
+                    row[3] += row[column] * (f - 1.0) / 2.0;
+                    row[column] *= f;
+                }
+            }
+            Some(affine)
+        }
+        affine => affine,
+    };
+
+    ArrayHeader {
+        shape,
+        spacing,
+        affine,
+        ..base.clone()
+    }
+}
+
+/// Downsample an array until a level takes up at most `max_bytes`, returning the headers and
+/// data of the coarser levels, finest first. The shapes of all levels, including the array
+/// itself, are listed in the `levels` of every header, including that of the array itself.
+pub fn build(header: &mut ArrayHeader, dtype: DType, data: &[u8], max_bytes: u64) -> Vec<(ArrayHeader, Vec<u8>)> {
+    let halved = halved_axes(&header.shape);
+    let reduction = Reduction::of(header, dtype);
+
+    let mut levels: Vec<(ArrayHeader, Vec<u8>)> = Vec::new();
+    let (mut shape, mut factors) = (header.shape.clone(), vec![1.0; header.shape.len()]);
+    loop {
+        let current = levels.last().map_or(data, |(_, data)| data.as_slice());
+        let can_shrink = shape.iter().zip(&halved).any(|(size, halved)| *halved && *size > 1);
+        if current.len() as u64 <= max_bytes || !can_shrink {
+            break;
+        }
+
+        // Axes that are down to a single element stop shrinking
+        for (axis, factor) in factors.iter_mut().enumerate() {
+            if halved[axis] && shape[axis] > 1 {
+                *factor *= 2.0;
+            }
+        }
+        let (next_shape, next) = downsample(&shape, dtype, current, &halved, reduction);
+        shape = next_shape;
+        levels.push((level_header(header, shape.clone(), &factors), next));
+    }
+
+    let shapes: Vec<Vec<usize>> = std::iter::once(header.shape.clone())
+        .chain(levels.iter().map(|(header, _)| header.shape.clone()))
+        .collect();
+    header.level = Some(0);
+    header.levels = Some(shapes.clone());
+    for (level, (header, _)) in levels.iter_mut().enumerate() {
+        header.level = Some(level + 1);
+        header.levels = Some(shapes.clone());
+    }
+    levels
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn halves_spatial_axes() {
+        assert_eq!(halved_axes(&[2, 8, 8, 8, 3]), [false, true, true, true, false]);
+        assert_eq!(halved_axes(&[8, 8, 8]), [true, true, true]);
+        assert_eq!(halved_axes(&[8, 8, 3]), [true, true, false]);
+        assert_eq!(halved_axes(&[8, 8, 8, 4]), [true, true, true, false]);
+        assert_eq!(halved_axes(&[8, 3]), [true, true]);
+    }
+
+    #[test]
+    fn averages_odd_sizes() {
+        // The element at (y, x) holds 5 * y + x
+        let data: Vec<u8> = (0..15).collect();
+        let (shape, data) = downsample(&[3, 5], DType::Uint8, &data, &[true, true], Reduction::Mean);
+        assert_eq!(shape, [2, 3]);
+        assert_eq!(data, [3, 5, 7, 11, 13, 14]);
+    }
+
+    #[test]
+    fn keeps_most_frequent_label() {
+        let data = [1, 2, 7, 7, 2, 2, 7, 3, 5, 5, 9, 9];
+        let (shape, data) = downsample(&[3, 4], DType::Uint8, &data, &[true, true], Reduction::Mode);
+        assert_eq!(shape, [2, 2]);
+        assert_eq!(data, [2, 7, 5, 9]);
+    }
+
+    #[test]
+    fn leaves_axes_that_are_not_halved() {
+        let data: Vec<u8> = (0..16).collect();
+        let halved = [false, true, true, true, false];
+        let (shape, data) = downsample(&[1, 1, 2, 4, 2], DType::Uint8, &data, &halved, Reduction::Mean);
+        assert_eq!(shape, [1, 1, 1, 2, 2]);
+        assert_eq!(data, [5, 6, 9, 10]);
+    }
+
+    #[test]
+    fn scales_spacing_and_affine_of_levels() {
+        let mut header = ArrayHeader::new("abc".to_owned(), vec![1, 1, 4, 4, 1], DType::Uint8);
+        header.spacing = Some(vec![1.0, 3.0, 0.5, 0.5, 1.0]);
+        header.affine = Some([
+            [0.5, 0.0, 0.0, 10.0],
+            [0.0, 0.5, 0.0, 20.0],
+            [0.0, 0.0, 3.0, 30.0],
+            [0.0, 0.0, 0.0, 1.0],
+        ]);
+        let levels = build(&mut header, DType::Uint8, &[0; 16], 1);
+        let shapes: Vec<_> = levels.iter().map(|(header, _)| header.shape.clone()).collect();
+        assert_eq!(shapes, [[1, 1, 2, 2, 1], [1, 1, 1, 1, 1]]);
+
+        // A coarse voxel is centered on the block of fine voxels it replaces, and the z axis,
+        // of a single slice, is left alone
+        let (first, _) = &levels[0];
+        assert_eq!(first.spacing, Some(vec![1.0, 3.0, 1.0, 1.0, 1.0]));
+        assert_eq!(
+            first.affine,
+            Some([
+                [1.0, 0.0, 0.0, 10.25],
+                [0.0, 1.0, 0.0, 20.25],
+                [0.0, 0.0, 3.0, 30.0],
+                [0.0, 0.0, 0.0, 1.0],
+            ])
+        );
+        let (second, _) = &levels[1];
+        assert_eq!(second.spacing, Some(vec![1.0, 3.0, 2.0, 2.0, 1.0]));
+        assert_eq!(second.affine.unwrap()[0], [2.0, 0.0, 0.0, 10.75]);
+        assert_eq!(second.affine.unwrap()[2], [0.0, 0.0, 3.0, 30.0]);
+
+        assert_eq!(header.levels.as_ref(), second.levels.as_ref());
+        assert_eq!((header.level, first.level, second.level), (Some(0), Some(1), Some(2)));
+    }
+    #[test]
+    fn scales_axes_that_stopped_shrinking_once() {
+        let mut header = ArrayHeader::new("abc".to_owned(), vec![1, 2, 8, 8, 1], DType::Uint8);
+        header.spacing = Some(vec![1.0, 3.0, 0.5, 0.5, 1.0]);
+        header.affine = Some([
+            [0.5, 0.0, 0.0, 10.0],
+            [0.0, 0.5, 0.0, 20.0],
+            [0.0, 0.0, 3.0, 30.0],
+            [0.0, 0.0, 0.0, 1.0],
+        ]);
+        let levels = build(&mut header, DType::Uint8, &[0; 128], 8);
+        let (last, _) = levels.last().unwrap();
+        assert_eq!(last.shape, [1, 1, 2, 2, 1]);
+
+        // The two slices were merged into one at the first level, and not since
+        assert_eq!(last.spacing, Some(vec![1.0, 6.0, 2.0, 2.0, 1.0]));
+        assert_eq!(last.affine.unwrap()[2], [0.0, 0.0, 6.0, 31.5]);
+    }
+}
@@ -0,0 +1,175 @@
+//! Statistics of arrays
+//!
+//! Viewers pick the initial window of an array from the distribution of its values. When
+//! started with `--statistics`, the server computes that distribution as arrays arrive, and
+//! sends it in their header, for every channel: the last axis of a 5D `(t, z, y, x, c)` array,
+//! or the whole array otherwise.
+//!
+//! ```not_rust
+//! {"min": 0, "max": 4095, "mean": 312.5, "count": 6553600,
+//!  "percentiles": [[0.5, 0], [1, 2], ..., [99.5, 2047]], "histogram": [1024, 96, ...]}
+//! ```
+//!
+//! Values that are not finite, such as NaN, are left out. The histogram has 256 equal bins from
+//! `min` to `max`, and percentiles are interpolated from a histogram 16 times as fine.
+
+use serde::{Deserialize, Serialize};
+
+use crate::dtype::DType;
+
+/// Percentiles computed for every channel
+pub const PERCENTILES: [f64; 9] = [0.5, 1.0, 2.0, 5.0, 50.0, 95.0, 98.0, 99.0, 99.5];
+
+/// Number of bins of the histogram sent to viewers
+const BINS: usize = 256;
+
+/// Number of finer bins every bin is split into to interpolate percentiles
+const RESOLUTION: usize = 16;
+
+/// Distribution of the values of a single channel
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct ChannelStatistics {
+    pub min: f64,
+    pub max: f64,
+    pub mean: f64,
+
+    /// Number of finite values the statistics are computed from
+    pub count: u64,
+
+    /// Pairs of a percentile, from 0 to 100, and the value at that percentile
+    pub percentiles: Vec<(f64, f64)>,
+
+    /// Number of values in each of the equal bins from `min` to `max`
+    pub histogram: Vec<u64>,
+}
+
+/// Index of the bin `value` falls into, out of `bins` equal bins from `min` to `max`
+fn bin(value: f64, min: f64, max: f64, bins: usize) -> usize {
+    if max <= min {
+        return 0;
+    }
+    (((value - min) / (max - min) * bins as f64) as usize).min(bins - 1)
+}
+
+/// Value below which `q` percent of the `count` values counted in the histogram `fine` lie
+fn percentile(fine: &[u64], count: u64, q: f64, min: f64, max: f64, dtype: DType) -> f64 {
+    let rank = q / 100.0 * count as f64;
+    let width = (max - min) / fine.len() as f64;
+    let mut below = 0;
+    let mut value = max;
+    for (i, n) in fine.iter().enumerate() {
+        if *n > 0 && (below + n) as f64 >= rank {
+            value = min + (i as f64 + (rank - below as f64) / *n as f64) * width;
+            break;
+        }
+        below += n;
+    }
+
+    let value = value.clamp(min, max);
+    match dtype {
+        DType::Float16 | DType::Float32 | DType::Float64 => value,
+        _ => value.round(),
+    }
+}
+
+/// Compute the statistics of every channel of an array of shape `shape`
+pub fn compute(shape: &[usize], dtype: DType, data: &[u8]) -> Vec<ChannelStatistics> {
+    let channels = match shape {
+        [_, _, _, _, c] => (*c).max(1),
+        _ => 1,
+    };
+    let values = || {
+        data.chunks_exact(dtype.itemsize())
+            .map(|item| dtype.value(item))
+            .enumerate()
+            .filter(|(_, value)| value.is_finite())
+            .map(|(i, value)| (i % channels, value))
+    };
+
+    // The range of every channel, which the bins of its histogram are spread over
+    let mut ranges = vec![(f64::INFINITY, f64::NEG_INFINITY, 0.0, 0u64); channels];
+    for (channel, value) in values() {
+        let (min, max, sum, count) = &mut ranges[channel];
+        *min = min.min(value);
+        *max = max.max(value);
+        *sum += value;
+        *count += 1;
+    }
+
+    let mut fine = vec![vec![0u64; BINS * RESOLUTION]; channels];
+    for (channel, value) in values() {
+        let (min, max, ..) = ranges[channel];
+        fine[channel][bin(value, min, max, BINS * RESOLUTION)] += 1;
+    }
+
+    ranges
+        .into_iter()
+        .zip(fine)
+        .map(|((min, max, sum, count), fine)| {
+            if count == 0 {
+                return ChannelStatistics {
+                    min: 0.0,
+                    max: 0.0,
+                    mean: 0.0,
+                    count,
+                    percentiles: Vec::new(),
+                    histogram: vec![0; BINS],
+                };
+            }
+            ChannelStatistics {
+                min,
+                max,
+                mean: sum / count as f64,
+                count,
+                percentiles: PERCENTILES
+                    .iter()
+                    .map(|q| (*q, percentile(&fine, count, *q, min, max, dtype)))
+                    .collect(),
+                histogram: fine.chunks(RESOLUTION).map(|bins| bins.iter().sum()).collect(),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interpolates_percentiles_within_bins() {
+        let fine = [1, 1, 1, 1];
+        let percentile = |q| percentile(&fine, 4, q, 0.0, 4.0, DType::Float32);
+        assert_eq!(percentile(0.0), 0.0);
+        assert_eq!(percentile(50.0), 2.0);
+        assert_eq!(percentile(62.5), 2.5);
+        assert_eq!(percentile(100.0), 4.0);
+    }
+
+    #[test]
+    fn skips_empty_bins() {
+        let fine = [2, 0, 0, 2];
+        assert_eq!(percentile(&fine, 4, 37.5, 0.0, 4.0, DType::Float32), 0.75);
+        assert_eq!(percentile(&fine, 4, 75.0, 0.0, 4.0, DType::Float32), 3.5);
+    }
+
+    #[test]
+    fn rounds_percentiles_of_integers() {
+        let fine = [2, 0, 0, 2];
+        assert_eq!(percentile(&fine, 4, 37.5, 0.0, 4.0, DType::Uint8), 1.0);
+        assert_eq!(percentile(&fine, 4, 75.0, 0.0, 4.0, DType::Int16), 4.0);
+    }
+
+    #[test]
+    fn computes_every_channel_without_nan() {
+        let values = [1.0f32, 10.0, f32::NAN, 20.0, 3.0, 30.0];
+        let data: Vec<u8> = values.iter().flat_map(|v| v.to_le_bytes()).collect();
+        let statistics = compute(&[1, 1, 1, 3, 2], DType::Float32, &data);
+
+        assert_eq!(statistics.len(), 2);
+        let (first, second) = (&statistics[0], &statistics[1]);
+        assert_eq!((first.min, first.max, first.mean, first.count), (1.0, 3.0, 2.0, 2));
+        assert_eq!((second.min, second.max, second.mean, second.count), (10.0, 30.0, 20.0, 3));
+        assert_eq!(second.histogram.iter().sum::<u64>(), 3);
+        assert_eq!(second.percentiles.len(), PERCENTILES.len());
+    }
+}
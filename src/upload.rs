@@ -0,0 +1,172 @@
+//! Chunked uploads
+//!
+//! Arrays that do not fit in a single websocket message are announced with an `upload_start`
+//! message and sent as a series of chunk frames, each carrying the offset at which its data
+//! belongs. Chunks can arrive in any order, and an upload survives the connection it was started
+//! on, so a client that reconnects can ask which bytes are still missing and resume from there.
+
+use std::fmt;
+use std::ops::Range;
+use std::time::{Duration, Instant};
+
+use crate::message::ArrayHeader;
+
+/// Time after which an upload that received no chunks is discarded
+pub const UPLOAD_EXPIRY: Duration = Duration::from_secs(10 * 60);
+
+/// An array that is being reassembled from chunks
+#[derive(Debug)]
+pub struct Upload {
+    pub header: ArrayHeader,
+    data: Vec<u8>,
+
+    // Sorted, non-overlapping ranges of bytes received so far
+    received: Vec<Range<u64>>,
+
+    last_activity: Instant,
+}
+
+/// Reasons a chunk can be rejected
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UploadError {
+    OutOfBounds { offset: u64, len: u64, size: u64 },
+}
+
+impl fmt::Display for UploadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UploadError::OutOfBounds { offset, len, size } => write!(
+                f,
+                "chunk of {len} bytes at offset {offset} exceeds the upload size of {size} bytes"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for UploadError {}
+
+impl Upload {
+    pub fn new(header: ArrayHeader, size: u64) -> Self {
+        Upload {
+            header,
+            data: vec![0; size as usize],
+            received: Vec::new(),
+            last_activity: Instant::now(),
+        }
+    }
+
+    /// Total size of the array in bytes
+    pub fn size(&self) -> u64 {
+        self.data.len() as u64
+    }
+
+    /// Copy a chunk into place.
+    pub fn write(&mut self, offset: u64, chunk: &[u8]) -> Result<(), UploadError> {
+        let len = chunk.len() as u64;
+        let end = offset.checked_add(len).filter(|end| *end <= self.size());
+        let Some(end) = end else {
+            return Err(UploadError::OutOfBounds {
+                offset,
+                len,
+                size: self.size(),
+            });
+        };
+
+        self.data[offset as usize..end as usize].copy_from_slice(chunk);
+        self.last_activity = Instant::now();
+
+        // Merge the new range with the ones it touches
+        self.received.push(offset..end);
+        self.received.sort_by_key(|r| r.start);
+        let mut merged: Vec<Range<u64>> = Vec::with_capacity(self.received.len());
+        for range in self.received.drain(..) {
+            match merged.last_mut() {
+                Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+                _ => merged.push(range),
+            }
+        }
+        self.received = merged;
+
+        Ok(())
+    }
+
+    /// Number of bytes received so far
+    pub fn received(&self) -> u64 {
+        self.received.iter().map(|r| r.end - r.start).sum()
+    }
+
+    /// Offset up to which all bytes were received, i.e. where a client should resume
+    pub fn next_offset(&self) -> u64 {
+        match self.received.first() {
+            Some(first) if first.start == 0 => first.end,
+            _ => 0,
+        }
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.next_offset() == self.size()
+    }
+
+    pub fn is_expired(&self, now: Instant) -> bool {
+        now.duration_since(self.last_activity) > UPLOAD_EXPIRY
+    }
+
+    /// The reassembled array
+    pub fn into_payload(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn upload(size: u64) -> Upload {
+        let header = serde_json::from_str(r#"{"hash": "abc", "shape": [10], "dtype": "uint8"}"#).unwrap();
+        Upload::new(header, size)
+    }
+
+    #[test]
+    fn merges_chunks_in_any_order() {
+        let mut upload = upload(10);
+        upload.write(4, &[4, 5]).unwrap();
+        upload.write(8, &[8, 9]).unwrap();
+        assert_eq!(upload.received, vec![4..6, 8..10]);
+        assert_eq!(upload.next_offset(), 0);
+
+        upload.write(0, &[0, 1, 2]).unwrap();
+        assert_eq!(upload.received, vec![0..3, 4..6, 8..10]);
+        assert_eq!(upload.next_offset(), 3);
+
+        // Touching and overlapping ranges are merged
+        upload.write(3, &[3]).unwrap();
+        assert_eq!(upload.received, vec![0..6, 8..10]);
+        upload.write(5, &[5, 6, 7]).unwrap();
+        assert_eq!(upload.received, vec![0..10]);
+
+        assert_eq!(upload.received(), 10);
+        assert!(upload.is_complete());
+        assert_eq!(upload.into_payload(), (0..10).collect::<Vec<u8>>());
+    }
+
+    #[test]
+    fn counts_resent_chunks_once() {
+        let mut upload = upload(10);
+        upload.write(0, &[0; 6]).unwrap();
+        upload.write(2, &[0; 2]).unwrap();
+        upload.write(0, &[0; 6]).unwrap();
+        assert_eq!(upload.received, vec![0..6]);
+        assert_eq!(upload.received(), 6);
+        assert_eq!(upload.next_offset(), 6);
+        assert!(!upload.is_complete());
+    }
+
+    #[test]
+    fn rejects_chunks_out_of_bounds() {
+        let mut upload = upload(10);
+        let err = upload.write(8, &[0; 3]).unwrap_err();
+        assert_eq!(err, UploadError::OutOfBounds { offset: 8, len: 3, size: 10 });
+        assert!(upload.write(u64::MAX, &[0]).is_err());
+        assert_eq!(upload.received(), 0);
+    }
+}
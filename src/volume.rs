@@ -0,0 +1,178 @@
+//! Volumes read from files
+//!
+//! Files in the formats the server understands are read into a [`Volume`]: a little-endian,
+//! C-ordered array together with the metadata the viewer needs to display it. Volumes are
+//! relayed to the viewers through the same header and binary messages as arrays sent by the
+//! Python runtime.
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use crate::dicom::{self, DicomError};
+use crate::dtype::DType;
+use crate::images::{self, ImageError};
+use crate::message::ArrayHeader;
+use crate::nifti::{self, NiftiError};
+use crate::npy::{self, NpyError};
+
+/// An array read from a file
+#[derive(Debug, Clone)]
+pub struct Volume {
+    pub shape: Vec<usize>,
+    pub dtype: DType,
+    pub data: Vec<u8>,
+
+    /// Distance between the centers of neighbouring elements, for every axis of `shape`
+    pub spacing: Option<Vec<f64>>,
+
+    /// Row-major matrix mapping voxel indices `(x, y, z, 1)` to world coordinates in mm, where
+    /// `x` indexes the last spatial axis of `shape`
+    pub affine: Option<[[f64; 4]; 4]>,
+
+    /// Window center and width to display the volume with
+    pub window: Option<(f64, f64)>,
+}
+
+impl Volume {
+    pub fn new(shape: Vec<usize>, dtype: DType, data: Vec<u8>) -> Self {
+        Volume {
+            shape,
+            dtype,
+            data,
+            spacing: None,
+            affine: None,
+            window: None,
+        }
+    }
+
+    /// The header describing the volume to the viewers of `hash`
+    pub fn header(&self, hash: String) -> ArrayHeader {
+        ArrayHeader {
+            spacing: self.spacing.clone(),
+            affine: self.affine,
+            window_center: self.window.map(|(center, _)| center),
+            window_width: self.window.map(|(_, width)| width),
+            ..ArrayHeader::new(hash, self.shape.clone(), self.dtype)
+        }
+    }
+}
+
+/// Move the components of a vector-valued volume, stored as the slowest varying axis, to the
+/// fastest varying one
+pub fn interleave(data: &[u8], components: usize, itemsize: usize) -> Vec<u8> {
+    let voxels = data.len() / itemsize / components;
+    let mut out = Vec::with_capacity(data.len());
+    for voxel in 0..voxels {
+        for component in 0..components {
+            let src = (component * voxels + voxel) * itemsize;
+            out.extend_from_slice(&data[src..src + itemsize]);
+        }
+    }
+    out
+}
+
+/// Reasons a file can not be opened
+#[derive(Debug)]
+pub enum OpenError {
+    Io(std::io::Error),
+    NotAllowed(PathBuf),
+    UnknownFormat(String),
+    Npy(NpyError),
+    Nifti(NiftiError),
+    Dicom(DicomError),
+    Image(ImageError),
+}
+
+impl fmt::Display for OpenError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OpenError::Io(err) => write!(f, "could not read file: {err}"),
+            OpenError::NotAllowed(path) => write!(f, "`{}` is not in a directory files can be opened from", path.display()),
+            OpenError::UnknownFormat(path) => write!(f, "unknown file format of `{path}`"),
+            OpenError::Npy(err) => err.fmt(f),
+            OpenError::Nifti(err) => err.fmt(f),
+            OpenError::Dicom(err) => err.fmt(f),
+            OpenError::Image(err) => err.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for OpenError {}
+
+impl From<std::io::Error> for OpenError {
+    fn from(err: std::io::Error) -> Self {
+        OpenError::Io(err)
+    }
+}
+
+impl From<NpyError> for OpenError {
+    fn from(err: NpyError) -> Self {
+        OpenError::Npy(err)
+    }
+}
+
+impl From<NiftiError> for OpenError {
+    fn from(err: NiftiError) -> Self {
+        OpenError::Nifti(err)
+    }
+}
+
+impl From<DicomError> for OpenError {
+    fn from(err: DicomError) -> Self {
+        OpenError::Dicom(err)
+    }
+}
+
+impl From<ImageError> for OpenError {
+    fn from(err: ImageError) -> Self {
+        OpenError::Image(err)
+    }
+}
+
+/// Whether `path`, with symbolic links resolved, lies in one of the `allowed` directories
+pub fn is_inside(path: &Path, allowed: &[PathBuf]) -> bool {
+    allowed.iter().any(|dir| dir.canonicalize().is_ok_and(|dir| path.starts_with(dir)))
+}
+
+/// The file or directory at `path`, with symbolic links resolved, if it lies in one of the
+/// `allowed` directories
+pub fn allowed_path(path: &Path, allowed: &[PathBuf]) -> Result<PathBuf, OpenError> {
+    let path = path.canonicalize()?;
+    if is_inside(&path, allowed) {
+        Ok(path)
+    } else {
+        Err(OpenError::NotAllowed(path))
+    }
+}
+
+/// Whether a directory holds PNG or TIFF images, rather than DICOM files
+fn has_images(dir: &Path) -> Result<bool, OpenError> {
+    for entry in std::fs::read_dir(dir)? {
+        if images::is_image(&entry?.path()) {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Read a file from disk, telling its format apart by its extension. Directories are read as a
+/// stack of PNG or TIFF slices if they hold any, and as a DICOM series, optionally selected by
+/// its series instance UID, otherwise. Compressed files are not inflated beyond `max_len` bytes.
+pub fn open(path: &Path, series: Option<&str>, max_len: u64) -> Result<Volume, OpenError> {
+    let name = path.to_string_lossy().to_lowercase();
+    std::fs::metadata(path)?;
+
+    if path.is_dir() && has_images(path)? {
+        Ok(images::read_stack(path, max_len)?)
+    } else if name.ends_with(".tif") || name.ends_with(".tiff") {
+        Ok(images::read_tiff(&std::fs::read(path)?, max_len)?)
+    } else if path.is_dir() || name.ends_with(".dcm") {
+        Ok(dicom::read_series(path, series, max_len)?)
+    } else if name.ends_with(".npy") || name.ends_with(".npz") {
+        Ok(npy::read(&std::fs::read(path)?, max_len)?)
+    } else if name.ends_with(".nii") || name.ends_with(".nii.gz") {
+        Ok(nifti::read(&std::fs::read(path)?, max_len)?)
+    } else {
+        Err(OpenError::UnknownFormat(path.display().to_string()))
+    }
+}
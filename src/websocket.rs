@@ -0,0 +1,1019 @@
+//! Websocket upgrades, with the permessage-deflate extension
+//!
+//! The extension (RFC 7692) compresses the text messages exchanged with a client, such as
+//! headers, view states and annotations, which cuts the bandwidth of viewers behind a forwarded
+//! port. Browsers and the Python `websockets` library offer it on every connection, and the
+//! server accepts it when run with `--permessage-deflate`. The server only compresses the text
+//! messages it sends, the binary payloads are left as they are, since they can be compressed
+//! with a codec of their own, see [`crate::compression`]. Clients may compress any message,
+//! binary ones included.
+//!
+//! The websocket library does not implement the extension, so [`Deflate`] sits between it and
+//! the connection: it inflates the compressed messages the client sends as they arrive, a
+//! bounded number of bytes at a time, before the library reads them, and deflates the text
+//! messages the library writes. Without `--permessage-deflate`, connections are upgraded by axum
+//! as they are.
+
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use axum::body::{boxed, Body, BoxBody};
+use axum::extract::{ws, FromRequestParts};
+use axum::http::{header, HeaderMap, Request, Response, StatusCode};
+use axum::response::IntoResponse;
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress};
+use futures::{Sink, Stream};
+use hyper::upgrade::Upgraded;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_tungstenite::tungstenite::handshake::derive_accept_key;
+use tokio_tungstenite::tungstenite::protocol::{frame::CloseFrame, Role, WebSocketConfig};
+use tokio_tungstenite::tungstenite::{Error, Message};
+use tokio_tungstenite::WebSocketStream;
+
+/// A websocket connection to a client, upgraded by axum, or by [`upgrade`] if the client
+/// negotiated permessage-deflate
+pub enum WebSocket {
+    Plain(Box<ws::WebSocket>),
+    Deflate(Box<WebSocketStream<Deflate<Upgraded>>>),
+}
+
+impl Stream for WebSocket {
+    type Item = Result<Message, Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        match self.get_mut() {
+            WebSocket::Plain(socket) => Poll::Ready(match ready!(Pin::new(socket).poll_next(cx)) {
+                Some(Ok(msg)) => Some(Ok(from_axum(msg))),
+                Some(Err(err)) => Some(Err(axum_error(err))),
+                None => None,
+            }),
+            WebSocket::Deflate(socket) => Pin::new(socket).poll_next(cx),
+        }
+    }
+}
+
+impl Sink<Message> for WebSocket {
+    type Error = Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        match self.get_mut() {
+            WebSocket::Plain(socket) => Pin::new(socket).poll_ready(cx).map_err(axum_error),
+            WebSocket::Deflate(socket) => Pin::new(socket).poll_ready(cx),
+        }
+    }
+
+    fn start_send(self: Pin<&mut Self>, msg: Message) -> Result<(), Error> {
+        match self.get_mut() {
+            WebSocket::Plain(socket) => {
+                let msg = into_axum(msg).ok_or_else(|| Error::Io(invalid_data("raw frames can not be sent")))?;
+                Pin::new(socket).start_send(msg).map_err(axum_error)
+            }
+            WebSocket::Deflate(socket) => Pin::new(socket).start_send(msg),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        match self.get_mut() {
+            WebSocket::Plain(socket) => Pin::new(socket).poll_flush(cx).map_err(axum_error),
+            WebSocket::Deflate(socket) => Pin::new(socket).poll_flush(cx),
+        }
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        match self.get_mut() {
+            WebSocket::Plain(socket) => Pin::new(socket).poll_close(cx).map_err(axum_error),
+            WebSocket::Deflate(socket) => Pin::new(socket).poll_close(cx),
+        }
+    }
+}
+
+fn axum_error(err: axum::Error) -> Error {
+    Error::Io(io::Error::other(err))
+}
+
+/// A message read by axum, as the websocket library represents it
+fn from_axum(msg: ws::Message) -> Message {
+    match msg {
+        ws::Message::Text(text) => Message::Text(text),
+        ws::Message::Binary(data) => Message::Binary(data),
+        ws::Message::Ping(data) => Message::Ping(data),
+        ws::Message::Pong(data) => Message::Pong(data),
+        ws::Message::Close(frame) => Message::Close(frame.map(|frame| CloseFrame {
+            code: frame.code.into(),
+            reason: frame.reason,
+        })),
+    }
+}
+
+/// A message to be written by axum, which does not write raw frames
+fn into_axum(msg: Message) -> Option<ws::Message> {
+    Some(match msg {
+        Message::Text(text) => ws::Message::Text(text),
+        Message::Binary(data) => ws::Message::Binary(data),
+        Message::Ping(data) => ws::Message::Ping(data),
+        Message::Pong(data) => ws::Message::Pong(data),
+        Message::Close(frame) => ws::Message::Close(frame.map(|frame| ws::CloseFrame {
+            code: frame.code.into(),
+            reason: frame.reason,
+        })),
+        Message::Frame(_) => return None,
+    })
+}
+
+/// Trailer that the sender strips from every deflated message, and the receiver appends again
+const DEFLATE_TRAILER: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+
+/// Largest, and default, LZ77 window of the extension
+const MAX_WINDOW_BITS: u8 = 15;
+
+/// Most inflated bytes handed on in a single frame, so that a message that inflates to many
+/// times its size takes up little memory at a time
+const INFLATE_CHUNK: usize = 64 * 1024;
+
+/// Settings of websocket connections
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Largest message, in bytes, before or after inflating it
+    pub max_message_size: usize,
+
+    /// Whether the permessage-deflate extension is accepted when a client offers it
+    pub permessage_deflate: bool,
+
+    /// Origins of web pages, besides the server itself, that may open a websocket
+    pub allowed_origins: Vec<String>,
+}
+
+/// The parameters of the permessage-deflate extension agreed on with a client
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeflateParams {
+    server_no_context_takeover: bool,
+    client_no_context_takeover: bool,
+
+    /// LZ77 window of the messages the server sends, if the client asked for a smaller one
+    server_max_window_bits: Option<u8>,
+}
+
+impl DeflateParams {
+    /// The parameters of the first permessage-deflate offer in a `Sec-WebSocket-Extensions`
+    /// header the server can accept
+    pub fn negotiate(offers: &str) -> Option<Self> {
+        offers.split(',').find_map(|offer| {
+            let mut params = offer.split(';').map(str::trim);
+            if params.next()? != "permessage-deflate" {
+                return None;
+            }
+
+            let mut accepted = DeflateParams {
+                server_no_context_takeover: false,
+                client_no_context_takeover: false,
+                server_max_window_bits: None,
+            };
+            for param in params {
+                let (name, value) = match param.split_once('=') {
+                    Some((name, value)) => (name.trim(), Some(value.trim().trim_matches('"'))),
+                    None => (param, None),
+                };
+                match (name, value) {
+                    ("server_no_context_takeover", None) => accepted.server_no_context_takeover = true,
+                    ("client_no_context_takeover", None) => accepted.client_no_context_takeover = true,
+
+                    // zlib can not write raw deflate streams with a window of 8 bits
+                    ("server_max_window_bits", Some(bits)) => match bits.parse() {
+                        Ok(bits @ 9..=MAX_WINDOW_BITS) => accepted.server_max_window_bits = Some(bits),
+                        _ => return None,
+                    },
+
+                    // Messages from the client are inflated with the largest window, which
+                    // reads those deflated with any window
+                    ("client_max_window_bits", _) => {}
+                    _ => return None,
+                }
+            }
+            Some(accepted)
+        })
+    }
+
+    /// The `Sec-WebSocket-Extensions` header that accepts the extension
+    fn response(&self) -> String {
+        let mut response = String::from("permessage-deflate");
+        if self.server_no_context_takeover {
+            response.push_str("; server_no_context_takeover");
+        }
+        if self.client_no_context_takeover {
+            response.push_str("; client_no_context_takeover");
+        }
+        if let Some(bits) = self.server_max_window_bits {
+            response.push_str(&format!("; server_max_window_bits={bits}"));
+        }
+        response
+    }
+}
+
+/// Whether header `name` holds `value`, or a list that contains it
+fn header_contains(headers: &HeaderMap, name: header::HeaderName, value: &str) -> bool {
+    headers
+        .get_all(name)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|v| v.trim().eq_ignore_ascii_case(value))
+}
+
+/// Whether a request comes from a client that may open a websocket, or post to the HTTP API:
+/// one that is not a browser, and so sends no `Origin`, or a page served by the server itself or
+/// from one of the `allowed` origins. This keeps other web pages the user visits from talking to
+/// the server.
+pub fn trusted_origin(headers: &HeaderMap, allowed: &[String]) -> bool {
+    let Some(origin) = headers.get(header::ORIGIN) else {
+        return true;
+    };
+    let Ok(origin) = origin.to_str() else {
+        return false;
+    };
+    let own = headers
+        .get(header::HOST)
+        .and_then(|host| host.to_str().ok())
+        .is_some_and(|host| origin == format!("http://{host}") || origin == format!("https://{host}"));
+    own || allowed.iter().any(|allowed| allowed.trim_end_matches('/') == origin)
+}
+
+/// Upgrade an HTTP request to a websocket connection, which is handed to `callback` once the
+/// upgrade completes
+pub async fn upgrade<F, Fut>(request: Request<Body>, config: Config, callback: F) -> Response<BoxBody>
+where
+    F: FnOnce(WebSocket) -> Fut + Send + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    if !trusted_origin(request.headers(), &config.allowed_origins) {
+        return Response::builder()
+            .status(StatusCode::FORBIDDEN)
+            .body(boxed(Body::from("`Origin` is not allowed to open a websocket")))
+            .unwrap();
+    }
+    if !config.permessage_deflate {
+        let (mut parts, _) = request.into_parts();
+        return match ws::WebSocketUpgrade::from_request_parts(&mut parts, &()).await {
+            Ok(upgrade) => upgrade
+                .max_message_size(config.max_message_size)
+                .max_frame_size(config.max_message_size)
+                .on_upgrade(move |socket| callback(WebSocket::Plain(Box::new(socket)))),
+            Err(rejection) => rejection.into_response(),
+        };
+    }
+    upgrade_deflate(request, config, callback)
+}
+
+/// Upgrade an HTTP request to a websocket connection that accepts permessage-deflate, if the
+/// client offers it
+fn upgrade_deflate<F, Fut>(mut request: Request<Body>, config: Config, callback: F) -> Response<BoxBody>
+where
+    F: FnOnce(WebSocket) -> Fut + Send + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    let headers = request.headers();
+    let refusal = if !header_contains(headers, header::CONNECTION, "upgrade") {
+        Some("`Connection` header did not include `upgrade`")
+    } else if !header_contains(headers, header::UPGRADE, "websocket") {
+        Some("`Upgrade` header did not include `websocket`")
+    } else if !header_contains(headers, header::SEC_WEBSOCKET_VERSION, "13") {
+        Some("`Sec-WebSocket-Version` header did not include `13`")
+    } else {
+        None
+    };
+    let key = headers.get(header::SEC_WEBSOCKET_KEY);
+    let (None, Some(key)) = (refusal, key) else {
+        return Response::builder()
+            .status(StatusCode::BAD_REQUEST)
+            .body(boxed(Body::from(refusal.unwrap_or("`Sec-WebSocket-Key` header missing"))))
+            .unwrap();
+    };
+    let accept = derive_accept_key(key.as_bytes());
+
+    let deflate = headers
+        .get_all(header::SEC_WEBSOCKET_EXTENSIONS)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .find_map(DeflateParams::negotiate);
+
+    let on_upgrade = hyper::upgrade::on(&mut request);
+    tokio::spawn(async move {
+        let upgraded = match on_upgrade.await {
+            Ok(upgraded) => upgraded,
+            Err(err) => {
+                println!("--- websocket upgrade failed: {err}");
+                return;
+            }
+        };
+
+        let websocket_config = WebSocketConfig {
+            max_message_size: Some(config.max_message_size),
+            max_frame_size: Some(config.max_message_size),
+            ..Default::default()
+        };
+        let stream = Deflate::new(upgraded, deflate, config.max_message_size);
+        let socket = WebSocketStream::from_raw_socket(stream, Role::Server, Some(websocket_config)).await;
+        callback(WebSocket::Deflate(Box::new(socket))).await;
+    });
+
+    let mut response = Response::builder()
+        .status(StatusCode::SWITCHING_PROTOCOLS)
+        .header(header::CONNECTION, "upgrade")
+        .header(header::UPGRADE, "websocket")
+        .header(header::SEC_WEBSOCKET_ACCEPT, accept);
+    if let Some(deflate) = deflate {
+        response = response.header(header::SEC_WEBSOCKET_EXTENSIONS, deflate.response());
+    }
+    response.body(boxed(Body::empty())).unwrap()
+}
+
+/// Header of a websocket frame
+#[derive(Debug, Clone, Copy)]
+struct FrameHeader {
+    fin: bool,
+    rsv1: bool,
+    opcode: u8,
+    mask: Option<[u8; 4]>,
+    len: u64,
+
+    /// Size of the header itself, in bytes
+    size: usize,
+}
+
+impl FrameHeader {
+    /// Parse the header at the start of `data`, if all of it is there
+    fn parse(data: &[u8]) -> Option<Self> {
+        let (first, second) = (*data.first()?, *data.get(1)?);
+        let (len, mut size) = match second & 0x7f {
+            126 => (u16::from_be_bytes(data.get(2..4)?.try_into().unwrap()) as u64, 4),
+            127 => (u64::from_be_bytes(data.get(2..10)?.try_into().unwrap()), 10),
+            len => (len as u64, 2),
+        };
+        let mask = if second & 0x80 != 0 {
+            let mask = data.get(size..size + 4)?.try_into().unwrap();
+            size += 4;
+            Some(mask)
+        } else {
+            None
+        };
+
+        Some(FrameHeader {
+            fin: first & 0x80 != 0,
+            rsv1: first & 0x40 != 0,
+            opcode: first & 0x0f,
+            mask,
+            len,
+            size,
+        })
+    }
+
+    fn is_control(&self) -> bool {
+        self.opcode & 0x08 != 0
+    }
+
+    /// Append the header of a frame to `out`
+    fn write(out: &mut Vec<u8>, fin: bool, opcode: u8, rsv1: bool, mask: Option<[u8; 4]>, len: usize) {
+        out.push(if fin { 0x80 } else { 0 } | if rsv1 { 0x40 } else { 0 } | opcode);
+        let masked = if mask.is_some() { 0x80 } else { 0 };
+        match len {
+            0..=125 => out.push(masked | len as u8),
+            126..=0xffff => {
+                out.push(masked | 126);
+                out.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            _ => {
+                out.push(masked | 127);
+                out.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+        if let Some(mask) = mask {
+            out.extend_from_slice(&mask);
+        }
+    }
+}
+
+/// The frames travelling in one direction of a connection
+#[derive(Debug, Default)]
+struct Frames {
+    /// Bytes that were not transcoded yet
+    input: Vec<u8>,
+
+    /// Transcoded bytes, of which those from `pos` on were not handed on yet
+    output: Vec<u8>,
+    pos: usize,
+
+    /// Bytes of the payload of the current frame that are handed on unchanged
+    passthrough: u64,
+
+    /// Opcode and payload of the message whose frames are being collected
+    message: Option<(u8, Vec<u8>)>,
+}
+
+impl Frames {
+    fn pending(&self) -> &[u8] {
+        &self.output[self.pos..]
+    }
+
+    /// Transcode the complete frames in `input` into `output`. The messages whose first frame
+    /// is `selected` are collected and, once complete, sent as a single frame with the payload
+    /// `convert` makes of them. All other frames are handed on unchanged.
+    fn transcode(
+        &mut self,
+        max_len: usize,
+        selected: impl Fn(&FrameHeader) -> bool,
+        mut convert: impl FnMut(&[u8]) -> io::Result<Vec<u8>>,
+        (rsv1, mask): (bool, Option<[u8; 4]>),
+    ) -> io::Result<()> {
+        let mut start = 0;
+        loop {
+            let rest = &self.input[start..];
+            if self.passthrough > 0 {
+                if rest.is_empty() {
+                    break;
+                }
+                let n = self.passthrough.min(rest.len() as u64) as usize;
+                self.output.extend_from_slice(&rest[..n]);
+                self.passthrough -= n as u64;
+                start += n;
+                continue;
+            }
+
+            let Some(header) = FrameHeader::parse(rest) else {
+                break;
+            };
+
+            // Control frames can come in between the frames of a message
+            let collecting = self.message.is_some();
+            if collecting && !header.is_control() && header.opcode != 0 {
+                return Err(invalid_data("expected a continuation frame"));
+            }
+            let collect = collecting || (header.opcode != 0 && selected(&header));
+            if header.is_control() || !collect {
+                self.output.extend_from_slice(&rest[..header.size]);
+                self.passthrough = header.len;
+                start += header.size;
+                continue;
+            }
+
+            let collected = self.message.as_ref().map_or(0, |(_, message)| message.len());
+            if header.len > (max_len - collected) as u64 {
+                return Err(invalid_data("message exceeds the size limit"));
+            }
+            let end = header.size + header.len as usize;
+            if rest.len() < end {
+                break;
+            }
+
+            let (_, message) = self.message.get_or_insert_with(|| (header.opcode, Vec::new()));
+            let offset = message.len();
+            message.extend_from_slice(&rest[header.size..end]);
+            if let Some(key) = header.mask {
+                message[offset..].iter_mut().zip(key.iter().cycle()).for_each(|(b, k)| *b ^= k);
+            }
+            start += end;
+
+            if header.fin {
+                let (opcode, message) = self.message.take().unwrap();
+                let payload = convert(&message)?;
+                FrameHeader::write(&mut self.output, true, opcode, rsv1, mask, payload.len());
+                self.output.extend_from_slice(&payload);
+            }
+        }
+
+        self.input.drain(..start);
+        Ok(())
+    }
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// The compressed message whose frames are being inflated
+#[derive(Debug)]
+struct Inflating {
+    /// Opcode of the frame the next inflated bytes are handed on in: that of the message for the
+    /// first frame, and a continuation after that
+    opcode: u8,
+
+    /// Mask of the current frame, and the bytes of its payload that were read
+    mask: Option<[u8; 4]>,
+    offset: u64,
+
+    /// Bytes of the payload of the current frame that were not read yet
+    remaining: u64,
+
+    /// Whether the current frame is the last of the message
+    fin: bool,
+
+    /// Bytes of the trailer that were inflated after the last frame
+    trailer: usize,
+
+    /// Bytes the message inflated to so far
+    len: usize,
+}
+
+impl Inflating {
+    /// Inflate the start of `data`, handing on at most `INFLATE_CHUNK` inflated bytes as a
+    /// masked frame, as the websocket library expects from a client. Returns the number of bytes
+    /// of `data` that were read and the number of bytes they inflated to.
+    fn inflate(
+        &mut self,
+        inflater: &mut Decompress,
+        data: &[u8],
+        out: &mut Vec<u8>,
+        max_len: usize,
+    ) -> io::Result<(usize, usize)> {
+        let mut inflated = Vec::with_capacity(INFLATE_CHUNK);
+        let before = inflater.total_in();
+        inflater
+            .decompress_vec(data, &mut inflated, FlushDecompress::Sync)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        let read = (inflater.total_in() - before) as usize;
+
+        self.len += inflated.len();
+        if self.len > max_len {
+            return Err(invalid_data("inflated message exceeds the size limit"));
+        }
+        if !inflated.is_empty() {
+            FrameHeader::write(out, false, self.opcode, false, Some([0; 4]), inflated.len());
+            out.extend_from_slice(&inflated);
+            self.opcode = 0;
+        }
+        Ok((read, inflated.len()))
+    }
+}
+
+/// Deflate a message for the client, without the trailer of the final block
+fn deflate(deflater: &mut Compress, data: &[u8]) -> Vec<u8> {
+    let mut input = data;
+    let mut out = Vec::with_capacity(data.len() / 2 + 64);
+    loop {
+        let before = deflater.total_in();
+        deflater
+            .compress_vec(input, &mut out, FlushCompress::Sync)
+            .expect("compressing into memory");
+        input = &input[(deflater.total_in() - before) as usize..];
+
+        if input.is_empty() && out.len() < out.capacity() {
+            break;
+        }
+        out.reserve(out.capacity());
+    }
+
+    if out.ends_with(&DEFLATE_TRAILER) {
+        out.truncate(out.len() - DEFLATE_TRAILER.len());
+    }
+    out
+}
+
+/// A connection that inflates the compressed messages read from it, and deflates the text
+/// messages written to it, if the permessage-deflate extension was negotiated
+pub struct Deflate<S> {
+    inner: S,
+    params: Option<DeflateParams>,
+    max_message_size: usize,
+    inflater: Decompress,
+    deflater: Compress,
+    inflating: Option<Inflating>,
+    read: Frames,
+    write: Frames,
+}
+
+impl<S> Deflate<S> {
+    pub fn new(inner: S, params: Option<DeflateParams>, max_message_size: usize) -> Self {
+        let window_bits = params.and_then(|p| p.server_max_window_bits).unwrap_or(MAX_WINDOW_BITS);
+        Deflate {
+            inner,
+            params,
+            max_message_size,
+            inflater: Decompress::new_with_window_bits(false, MAX_WINDOW_BITS),
+            deflater: Compress::new_with_window_bits(Compression::default(), false, window_bits),
+            inflating: None,
+            read: Frames::default(),
+            write: Frames::default(),
+        }
+    }
+}
+
+impl<S> Deflate<S> {
+    /// Inflate the compressed messages in the bytes read from the client, until `INFLATE_CHUNK`
+    /// bytes are waiting to be handed on, or more bytes have to be read. Compressed messages are
+    /// marked by RSV1 on their first frame, all other frames are handed on unchanged.
+    fn inflate_frames(&mut self, params: DeflateParams) -> io::Result<()> {
+        let Deflate { read: frames, inflating, inflater, max_message_size: max_len, .. } = self;
+        let mut start = 0;
+        while frames.pending().len() < INFLATE_CHUNK {
+            let rest = &frames.input[start..];
+            if frames.passthrough > 0 {
+                if rest.is_empty() {
+                    break;
+                }
+                let n = frames.passthrough.min(rest.len() as u64) as usize;
+                frames.output.extend_from_slice(&rest[..n]);
+                frames.passthrough -= n as u64;
+                start += n;
+                continue;
+            }
+
+            // The payload of a frame of a compressed message is inflated as it arrives
+            if let Some(message) = inflating.as_mut().filter(|message| message.remaining > 0) {
+                if rest.is_empty() {
+                    break;
+                }
+                let n = message.remaining.min(rest.len() as u64) as usize;
+                let mut data = rest[..n].to_vec();
+                if let Some(key) = message.mask {
+                    let key = key.iter().cycle().skip((message.offset % 4) as usize);
+                    data.iter_mut().zip(key).for_each(|(b, k)| *b ^= k);
+                }
+                let (read, inflated) = message.inflate(inflater, &data, &mut frames.output, *max_len)?;
+                if read == 0 && inflated == 0 {
+                    return Err(invalid_data("compressed message can not be inflated"));
+                }
+                message.offset += read as u64;
+                message.remaining -= read as u64;
+                start += read;
+                continue;
+            }
+
+            // Once a frame is read, the inflater hands on what it held back, and the last frame
+            // is followed by the trailer the client stripped
+            if let Some(message) = inflating.as_mut() {
+                let trailer = if message.fin { &DEFLATE_TRAILER[message.trailer..] } else { &[] };
+                let (read, inflated) = message.inflate(inflater, trailer, &mut frames.output, *max_len)?;
+                message.trailer += read;
+                if read > 0 || inflated > 0 {
+                    continue;
+                }
+                if message.fin {
+                    FrameHeader::write(&mut frames.output, true, message.opcode, false, Some([0; 4]), 0);
+                    *inflating = None;
+                    if params.client_no_context_takeover {
+                        inflater.reset(false);
+                    }
+                    continue;
+                }
+            }
+
+            let Some(header) = FrameHeader::parse(rest) else {
+                break;
+            };
+
+            // Control frames can come in between the frames of a message
+            let compressed = inflating.is_some() || (header.rsv1 && header.opcode != 0);
+            if header.is_control() || !compressed {
+                frames.output.extend_from_slice(&rest[..header.size]);
+                frames.passthrough = header.len;
+                start += header.size;
+                continue;
+            }
+            if inflating.is_some() && header.opcode != 0 {
+                return Err(invalid_data("expected a continuation frame"));
+            }
+
+            let message = inflating.get_or_insert(Inflating {
+                opcode: header.opcode,
+                mask: None,
+                offset: 0,
+                remaining: 0,
+                fin: false,
+                trailer: 0,
+                len: 0,
+            });
+            message.mask = header.mask;
+            message.offset = 0;
+            message.remaining = header.len;
+            message.fin = header.fin;
+            start += header.size;
+        }
+
+        frames.input.drain(..start);
+        Ok(())
+    }
+}
+
+impl<S: AsyncWrite + Unpin> Deflate<S> {
+    /// Write the transcoded bytes that are waiting to the connection
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.write.pending().is_empty() {
+            let n = ready!(Pin::new(&mut self.inner).poll_write(cx, self.write.pending()))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.write.pos += n;
+        }
+        self.write.output.clear();
+        self.write.pos = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Deflate<S> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let Some(params) = this.params else {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        };
+
+        loop {
+            let pending = this.read.pending();
+            if !pending.is_empty() {
+                let n = pending.len().min(buf.remaining());
+                buf.put_slice(&pending[..n]);
+                this.read.pos += n;
+                if this.read.pos == this.read.output.len() {
+                    this.read.output.clear();
+                    this.read.pos = 0;
+                }
+                return Poll::Ready(Ok(()));
+            }
+
+            this.inflate_frames(params)?;
+            if !this.read.pending().is_empty() {
+                continue;
+            }
+
+            let mut chunk = [0; 16 * 1024];
+            let mut chunk = ReadBuf::new(&mut chunk);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut chunk))?;
+            if chunk.filled().is_empty() {
+                return Poll::Ready(Ok(()));
+            }
+            this.read.input.extend_from_slice(chunk.filled());
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Deflate<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, data: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let Some(params) = this.params else {
+            return Pin::new(&mut this.inner).poll_write(cx, data);
+        };
+        ready!(this.poll_drain(cx))?;
+
+        // The payloads of frames that are handed on unchanged, such as binary payloads, are
+        // written straight through
+        let frames = &mut this.write;
+        if frames.input.is_empty() {
+            if frames.passthrough > 0 {
+                let n = frames.passthrough.min(data.len() as u64) as usize;
+                let n = ready!(Pin::new(&mut this.inner).poll_write(cx, &data[..n]))?;
+                frames.passthrough -= n as u64;
+                return Poll::Ready(Ok(n));
+            }
+            if let Some(header) = FrameHeader::parse(data) {
+                if header.opcode != 1 {
+                    frames.output.extend_from_slice(&data[..header.size]);
+                    frames.passthrough = header.len;
+                    return Poll::Ready(Ok(header.size));
+                }
+            }
+        }
+
+        // Text messages are deflated, the websocket library sends each as a single frame
+        frames.input.extend_from_slice(data);
+        let deflater = &mut this.deflater;
+        frames.transcode(
+            usize::MAX,
+            |header| header.opcode == 1 && header.fin && !header.rsv1,
+            |message| {
+                let deflated = deflate(deflater, message);
+                if params.server_no_context_takeover {
+                    deflater.reset();
+                }
+                Ok(deflated)
+            },
+            (true, None),
+        )?;
+        Poll::Ready(Ok(data.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    const KEY: [u8; 4] = [0x37, 0xfa, 0x21, 0x3d];
+
+    /// A frame as a client sends it, masked with `KEY`
+    fn client_frame(opcode: u8, fin: bool, rsv1: bool, payload: &[u8]) -> Vec<u8> {
+        let mut frame = Vec::new();
+        FrameHeader::write(&mut frame, fin, opcode, rsv1, Some(KEY), payload.len());
+        frame.extend(payload.iter().zip(KEY.iter().cycle()).map(|(b, k)| b ^ k));
+        frame
+    }
+
+    /// Split the frames in `data` into their headers and unmasked payloads
+    fn frames(mut data: &[u8]) -> Vec<(FrameHeader, Vec<u8>)> {
+        let mut frames = Vec::new();
+        while !data.is_empty() {
+            let header = FrameHeader::parse(data).unwrap();
+            let end = header.size + header.len as usize;
+            let mut payload = data[header.size..end].to_vec();
+            if let Some(key) = header.mask {
+                payload.iter_mut().zip(key.iter().cycle()).for_each(|(b, k)| *b ^= k);
+            }
+            frames.push((header, payload));
+            data = &data[end..];
+        }
+        frames
+    }
+
+    /// Join the frames of fragmented messages, leaving control frames as they are
+    fn messages(frames: Vec<(FrameHeader, Vec<u8>)>) -> Vec<(u8, Vec<u8>)> {
+        let mut messages: Vec<(u8, Vec<u8>)> = Vec::new();
+        let mut fragmented: Option<usize> = None;
+        for (header, payload) in frames {
+            match (header.opcode, fragmented) {
+                (0, Some(i)) => messages[i].1.extend(payload),
+                (opcode, _) if opcode & 0x08 != 0 => {
+                    messages.push((opcode, payload));
+                    continue;
+                }
+                (opcode, _) => {
+                    messages.push((opcode, payload));
+                    fragmented = Some(messages.len() - 1);
+                }
+            }
+            if header.fin {
+                fragmented = None;
+            }
+        }
+        messages
+    }
+
+    /// What the websocket library reads after the client sent `data`
+    async fn read_from_client(params: DeflateParams, max_len: usize, data: &[u8]) -> io::Result<Vec<u8>> {
+        let (mut client, server) = tokio::io::duplex(1 << 20);
+        client.write_all(data).await.unwrap();
+        drop(client);
+
+        let mut out = Vec::new();
+        Deflate::new(server, Some(params), max_len).read_to_end(&mut out).await?;
+        Ok(out)
+    }
+
+    /// What the client reads after the websocket library wrote `data`
+    async fn write_to_client(params: DeflateParams, data: &[u8]) -> Vec<u8> {
+        let (mut client, server) = tokio::io::duplex(1 << 20);
+        let mut stream = Deflate::new(server, Some(params), usize::MAX);
+        stream.write_all(data).await.unwrap();
+        stream.flush().await.unwrap();
+        drop(stream);
+
+        let mut out = Vec::new();
+        client.read_to_end(&mut out).await.unwrap();
+        out
+    }
+
+    fn params() -> DeflateParams {
+        DeflateParams::negotiate("permessage-deflate").unwrap()
+    }
+
+    fn client_deflater() -> Compress {
+        Compress::new_with_window_bits(Compression::default(), false, MAX_WINDOW_BITS)
+    }
+
+    #[test]
+    fn negotiates_browser_and_python_offers() {
+        let chrome = DeflateParams::negotiate("permessage-deflate; client_max_window_bits").unwrap();
+        assert_eq!(chrome, params());
+        assert_eq!(chrome.response(), "permessage-deflate");
+
+        let firefox = DeflateParams::negotiate("permessage-deflate").unwrap();
+        assert_eq!(firefox.response(), "permessage-deflate");
+
+        let python = "permessage-deflate; server_max_window_bits=12; client_max_window_bits=12";
+        let python = DeflateParams::negotiate(python).unwrap();
+        assert_eq!(python.server_max_window_bits, Some(12));
+        assert_eq!(python.response(), "permessage-deflate; server_max_window_bits=12");
+    }
+
+    #[test]
+    fn negotiates_first_acceptable_offer() {
+        assert_eq!(DeflateParams::negotiate("x-webkit-deflate-frame"), None);
+        assert_eq!(DeflateParams::negotiate("permessage-deflate; unknown"), None);
+
+        let offers = "permessage-deflate; server_max_window_bits=8, permessage-deflate; client_no_context_takeover";
+        let accepted = DeflateParams::negotiate(offers).unwrap();
+        assert!(accepted.client_no_context_takeover);
+        assert_eq!(accepted.response(), "permessage-deflate; client_no_context_takeover");
+    }
+
+    #[tokio::test]
+    async fn inflates_fragmented_message_around_control_frame() {
+        let text = "{\"type\": \"header\", \"hash\": \"abc\"}".repeat(20);
+        let deflated = deflate(&mut client_deflater(), text.as_bytes());
+        let (first, second) = deflated.split_at(deflated.len() / 2);
+
+        let data = [
+            client_frame(1, false, true, first),
+            client_frame(9, true, false, b"ping"),
+            client_frame(0, true, false, second),
+        ]
+        .concat();
+        let read = frames(&read_from_client(params(), 1 << 20, &data).await.unwrap());
+        assert!(read.iter().all(|(header, _)| !header.rsv1 && header.mask.is_some()));
+
+        // The first frame is inflated before the ping arrives
+        let read = messages(read);
+        assert_eq!(read.len(), 2);
+        assert_eq!((read[0].0, read[0].1.as_slice()), (1, text.as_bytes()));
+        assert_eq!((read[1].0, read[1].1.as_slice()), (9, &b"ping"[..]));
+    }
+
+    #[tokio::test]
+    async fn inflates_messages_sharing_a_context() {
+        let mut deflater = client_deflater();
+        let text = b"{\"type\": \"view\", \"hash\": \"abc\", \"slice\": 12}";
+        let data = [
+            client_frame(1, true, true, &deflate(&mut deflater, text)),
+            client_frame(1, true, true, &deflate(&mut deflater, text)),
+        ]
+        .concat();
+        let read = messages(frames(&read_from_client(params(), 1 << 20, &data).await.unwrap()));
+
+        assert_eq!(read.len(), 2);
+        assert!(read.iter().all(|(_, payload)| payload == text));
+    }
+
+    #[tokio::test]
+    async fn inflates_messages_without_client_context_takeover() {
+        let params = DeflateParams::negotiate("permessage-deflate; client_no_context_takeover").unwrap();
+        let texts: [&[u8]; 2] = [b"{\"type\": \"view\", \"slice\": 12}", b"{\"type\": \"view\", \"slice\": 13}"];
+        let data: Vec<u8> = texts
+            .iter()
+            .flat_map(|text| client_frame(1, true, true, &deflate(&mut client_deflater(), text)))
+            .collect();
+        let read = messages(frames(&read_from_client(params, 1 << 20, &data).await.unwrap()));
+
+        assert_eq!(read.len(), 2);
+        assert_eq!(read[0].1, texts[0]);
+        assert_eq!(read[1].1, texts[1]);
+    }
+
+    #[tokio::test]
+    async fn refuses_messages_that_inflate_beyond_limit() {
+        let zeros = vec![0; 64 * 1024];
+        let data = client_frame(1, true, true, &deflate(&mut client_deflater(), &zeros));
+        assert!(data.len() < 1024);
+
+        let err = read_from_client(params(), 1024, &data).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(read_from_client(params(), zeros.len(), &data).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn inflates_binary_messages_in_bounded_frames() {
+        let binary: Vec<u8> = (0..4 * INFLATE_CHUNK).map(|i| (i / 1000) as u8).collect();
+        let deflated = deflate(&mut client_deflater(), &binary);
+        let data = [client_frame(2, true, true, &deflated), client_frame(1, true, false, b"{}")].concat();
+        let read = frames(&read_from_client(params(), binary.len(), &data).await.unwrap());
+
+        assert!(read.len() > 4);
+        assert!(read.iter().all(|(header, payload)| !header.rsv1 && payload.len() <= INFLATE_CHUNK));
+        let read = messages(read);
+        assert_eq!(read, [(2, binary), (1, b"{}".to_vec())]);
+    }
+
+    #[tokio::test]
+    async fn deflates_text_next_to_binary_frames() {
+        let text = "{\"type\": \"header\", \"hash\": \"abc\"}".repeat(20);
+        let binary: Vec<u8> = (0..300).map(|i| i as u8).collect();
+        let mut data = Vec::new();
+        for (opcode, payload) in [(2, &binary[..]), (1, text.as_bytes()), (2, &binary[..])] {
+            FrameHeader::write(&mut data, true, opcode, false, None, payload.len());
+            data.extend_from_slice(payload);
+        }
+        let written = frames(&write_to_client(params(), &data).await);
+
+        assert_eq!(written.len(), 3);
+        for (header, payload) in [&written[0], &written[2]] {
+            assert_eq!((header.opcode, header.rsv1), (2, false));
+            assert_eq!(payload, &binary);
+        }
+        let (header, payload) = &written[1];
+        assert_eq!((header.opcode, header.rsv1, header.mask), (1, true, None));
+        assert!(payload.len() < text.len());
+        let mut inflated = Vec::with_capacity(2 * text.len());
+        let mut inflater = Decompress::new_with_window_bits(false, MAX_WINDOW_BITS);
+        inflater.decompress_vec(&[payload, &DEFLATE_TRAILER[..]].concat(), &mut inflated, FlushDecompress::Sync).unwrap();
+        assert_eq!(inflated, text.as_bytes());
+    }
+}
@@ -0,0 +1,162 @@
+//! Zarr stores
+//!
+//! Volumes too large to send as a single array can be viewed from a Zarr store on the disk of
+//! the server instead. Opening a store announces it to the viewers of a hash with a `zarr`
+//! message, after which a viewer fetches the metadata and only the chunks, or resolution levels,
+//! it needs:
+//!
+//! ```not_rust
+//! GET /api/zarr/dev/.zattrs
+//! GET /api/zarr/dev/0/.zarray
+//! GET /api/zarr/dev/0/0.0.1.2.3
+//! ```
+//!
+//! Both Zarr v2 and v3 stores are supported, as are OME-Zarr images, whose `multiscales`
+//! metadata is sent along in the announcement. Missing chunks are answered with `404 Not
+//! Found`, which Zarr readers take to mean a chunk that holds only the fill value.
+//!
+//! See https://zarr-specs.readthedocs.io and https://ngff.openmicroscopy.org
+
+use std::fmt;
+use std::path::{Component, Path, PathBuf};
+
+use serde_json::Value;
+
+/// Metadata files that mark the root of a Zarr v2 store
+const V2_METADATA: &[&str] = &[".zgroup", ".zarray"];
+
+/// Metadata file that marks the root of a Zarr v3 store
+const V3_METADATA: &str = "zarr.json";
+
+/// A Zarr store on the disk of the server
+#[derive(Debug, Clone, PartialEq)]
+pub struct Store {
+    pub root: PathBuf,
+    pub zarr_format: u8,
+
+    /// The `multiscales` metadata of an OME-Zarr image, listing its resolution levels
+    pub multiscales: Option<Value>,
+}
+
+/// Reasons a Zarr store can not be opened
+#[derive(Debug)]
+pub enum ZarrError {
+    Io(std::io::Error),
+    InvalidMetadata(PathBuf, serde_json::Error),
+}
+
+impl fmt::Display for ZarrError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ZarrError::Io(err) => write!(f, "could not read Zarr store: {err}"),
+            ZarrError::InvalidMetadata(path, err) => {
+                write!(f, "invalid Zarr metadata in `{}`: {err}", path.display())
+            }
+        }
+    }
+}
+
+impl std::error::Error for ZarrError {}
+
+impl From<std::io::Error> for ZarrError {
+    fn from(err: std::io::Error) -> Self {
+        ZarrError::Io(err)
+    }
+}
+
+/// Whether `path` is the root of a Zarr store
+pub fn is_store(path: &Path) -> bool {
+    path.is_dir()
+        && (path.join(V3_METADATA).is_file() || V2_METADATA.iter().any(|name| path.join(name).is_file()))
+}
+
+/// Read a JSON metadata file, if it exists
+fn metadata(path: &Path) -> Result<Option<Value>, ZarrError> {
+    match std::fs::read(path) {
+        Ok(data) => serde_json::from_slice(&data)
+            .map(Some)
+            .map_err(|err| ZarrError::InvalidMetadata(path.to_owned(), err)),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+impl Store {
+    /// Open the store at `root`, reading its version and OME-Zarr metadata
+    pub fn open(root: &Path) -> Result<Self, ZarrError> {
+        let root = root.canonicalize()?;
+
+        // OME-Zarr keeps its metadata in the attributes of the root group: in `.zattrs` for
+        // Zarr v2, and under `ome` in `zarr.json` for Zarr v3
+        let (zarr_format, attributes) = match metadata(&root.join(V3_METADATA))? {
+            Some(meta) => (3, meta.get("attributes").and_then(|a| a.get("ome")).cloned()),
+            None => (2, metadata(&root.join(".zattrs"))?),
+        };
+        let multiscales = attributes.and_then(|a| a.get("multiscales").cloned());
+
+        Ok(Store {
+            root,
+            zarr_format,
+            multiscales,
+        })
+    }
+
+    /// Path of the file that holds `key`, refusing keys that would leave the store
+    pub fn resolve(&self, key: &str) -> Option<PathBuf> {
+        let key = Path::new(key);
+        if !key.components().all(|c| matches!(c, Component::Normal(_))) {
+            return None;
+        }
+        Some(self.root.join(key))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store() -> Store {
+        Store {
+            root: PathBuf::from("/data/brain.zarr"),
+            zarr_format: 2,
+            multiscales: None,
+        }
+    }
+
+    #[test]
+    fn resolves_keys_inside_the_store() {
+        let store = store();
+        assert_eq!(store.resolve(".zattrs"), Some(PathBuf::from("/data/brain.zarr/.zattrs")));
+        assert_eq!(store.resolve("0/c/0/1/2"), Some(PathBuf::from("/data/brain.zarr/0/c/0/1/2")));
+    }
+
+    #[test]
+    fn rejects_keys_that_leave_the_store() {
+        let store = store();
+        for key in ["..", "../secret", "0/../../secret", "/etc/passwd", "./0/.zarray"] {
+            assert_eq!(store.resolve(key), None, "{key}");
+        }
+    }
+
+    #[test]
+    fn reads_ome_metadata_of_v2_and_v3_stores() {
+        let dir = std::env::temp_dir().join(format!("tunnelvision-zarr-{}", std::process::id()));
+        let (v2, v3) = (dir.join("v2.zarr"), dir.join("v3.zarr"));
+        std::fs::create_dir_all(&v2).unwrap();
+        std::fs::create_dir_all(&v3).unwrap();
+        std::fs::write(v2.join(".zgroup"), br#"{"zarr_format": 2}"#).unwrap();
+        std::fs::write(v2.join(".zattrs"), br#"{"multiscales": [{"version": "0.4"}]}"#).unwrap();
+        let v3_meta = br#"{"zarr_format": 3, "node_type": "group", "attributes": {"ome": {"multiscales": [{}]}}}"#;
+        std::fs::write(v3.join("zarr.json"), v3_meta).unwrap();
+
+        let stores = (is_store(&v2), is_store(&v3), is_store(&dir), Store::open(&v2), Store::open(&v3));
+        std::fs::remove_dir_all(&dir).unwrap();
+        let (v2_is_store, v3_is_store, dir_is_store, v2, v3) = stores;
+        assert!(v2_is_store && v3_is_store && !dir_is_store);
+
+        let (v2, v3) = (v2.unwrap(), v3.unwrap());
+        assert_eq!((v2.zarr_format, v3.zarr_format), (2, 3));
+        assert_eq!(v2.multiscales, Some(serde_json::json!([{"version": "0.4"}])));
+        assert_eq!(v3.multiscales, Some(serde_json::json!([{}])));
+    }
+}